@@ -0,0 +1,54 @@
+use crate::{error::ApiError, models::AppState};
+use axum::{
+    extract::State,
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+// Only the digests of the keys are configured, which also keeps the time taken independent of
+// how much of the key was right. Without digests the admin routes are disabled.
+fn is_valid_key(key: &str, digests: &[String]) -> bool {
+    let digest = format!("{:x}", Sha256::digest(key.as_bytes()));
+    digests
+        .iter()
+        .any(|expected| expected.eq_ignore_ascii_case(&digest))
+}
+
+// Rejects the requests without the admin API key
+pub async fn require_api_key<B>(
+    State(state): State<Arc<AppState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|key| is_valid_key(key, &state.conf.admin.api_key_hashes));
+    if !authorized {
+        return ApiError::Unauthorized("Invalid API key".to_string()).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod admin_tests {
+    use super::is_valid_key;
+
+    // printf %s secret | sha256sum
+    const SECRET: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    #[test]
+    fn test_is_valid_key() {
+        let digests = vec!["xxx".to_string(), SECRET.to_uppercase()];
+        assert!(is_valid_key("secret", &digests));
+        assert!(!is_valid_key("secre", &digests));
+        assert!(!is_valid_key("", &digests));
+        assert!(!is_valid_key(SECRET, &digests));
+        assert!(!is_valid_key("secret", &[]));
+    }
+}
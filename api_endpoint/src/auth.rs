@@ -0,0 +1,489 @@
+use crate::{config::Auth, error::ApiError, models::AppState};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use sales_common::{crypto::TokenSigner, utils::to_hex};
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use starknet::{
+    core::{
+        crypto::compute_hash_on_elements,
+        types::{BlockId, BlockTag, FieldElement, FunctionCall, StarknetError},
+        utils::{cairo_short_string_to_felt, get_selector_from_name, starknet_keccak},
+    },
+    providers::{
+        jsonrpc::{HttpTransport, JsonRpcClient},
+        MaybeUnknownErrorCode, Provider, ProviderError, StarknetErrorWithMessage, Url,
+    },
+};
+use std::{fmt, sync::Arc};
+
+// Wallets prove they control an address by signing SNIP-12 typed data (revision 0) embedding a
+// challenge we issued, the account contract checks the signature. They then get a session token
+// signed with the `token_key` of the `[auth]` section, sent as "Authorization: Bearer <token>".
+
+// Purposes of the signed challenges and sessions
+const CHALLENGE: &str = "auth_challenge";
+const SESSION: &str = "auth_session";
+
+const DOMAIN_NAME: &str = "StarkNet ID";
+const DOMAIN_VERSION: &str = "1";
+const DOMAIN_TYPE: &str = "StarkNetDomain(name:felt,version:felt,chainId:felt)";
+const LOGIN_TYPE: &str = "Login(nonce:felt)";
+const MESSAGE_PREFIX: &str = "StarkNet Message";
+
+// SNIP-6 accounts answer 'VALID' to a valid signature, older accounts answer 1
+const VALID: &str = "VALID";
+const ENTRY_POINTS: [&str; 2] = ["is_valid_signature", "isValidSignature"];
+
+#[derive(Debug)]
+pub enum AuthError {
+    Config(String),
+    InvalidChallenge,
+    InvalidSignature,
+    Rpc(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Config(err) => write!(f, "invalid config: {}", err),
+            AuthError::InvalidChallenge => write!(f, "invalid or expired challenge"),
+            AuthError::InvalidSignature => write!(f, "invalid signature"),
+            AuthError::Rpc(err) => write!(f, "rpc request failed: {}", err),
+        }
+    }
+}
+
+#[async_trait]
+pub trait AccountVerifier: Send + Sync {
+    // Whether the account contract at `address` accepts `signature` of `hash`
+    async fn is_valid_signature(
+        &self,
+        address: FieldElement,
+        hash: FieldElement,
+        signature: &[FieldElement],
+    ) -> Result<bool, AuthError>;
+}
+
+// Asks the account contract through the starknet_call method of a JSON-RPC node
+pub struct RpcAccountVerifier {
+    provider: JsonRpcClient<HttpTransport>,
+}
+
+impl RpcAccountVerifier {
+    pub fn new(rpc_url: &str) -> Result<Self, AuthError> {
+        let rpc_url = Url::parse(rpc_url).map_err(|err| AuthError::Config(err.to_string()))?;
+        Ok(RpcAccountVerifier {
+            provider: JsonRpcClient::new(HttpTransport::new(rpc_url)),
+        })
+    }
+
+    // The result of the call, or None when the contract reverted or doesn't exist
+    async fn call(
+        &self,
+        address: FieldElement,
+        entry_point: &str,
+        calldata: &[FieldElement],
+    ) -> Result<Option<Vec<FieldElement>>, AuthError> {
+        let call = FunctionCall {
+            contract_address: address,
+            entry_point_selector: get_selector_from_name(entry_point)
+                .map_err(|err| AuthError::Rpc(err.to_string()))?,
+            calldata: calldata.to_vec(),
+        };
+        match self
+            .provider
+            .call(call, BlockId::Tag(BlockTag::Latest))
+            .await
+        {
+            Ok(result) => Ok(Some(result)),
+            Err(ProviderError::StarknetError(StarknetErrorWithMessage {
+                code:
+                    MaybeUnknownErrorCode::Known(
+                        StarknetError::ContractError | StarknetError::ContractNotFound,
+                    ),
+                ..
+            })) => Ok(None),
+            Err(err) => Err(AuthError::Rpc(err.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl AccountVerifier for RpcAccountVerifier {
+    async fn is_valid_signature(
+        &self,
+        address: FieldElement,
+        hash: FieldElement,
+        signature: &[FieldElement],
+    ) -> Result<bool, AuthError> {
+        let valid = short_string(VALID)?;
+        let mut calldata = vec![hash, FieldElement::from(signature.len() as u64)];
+        calldata.extend_from_slice(signature);
+        // accounts implementing neither entry point revert on both
+        for entry_point in ENTRY_POINTS {
+            if let Some(result) = self.call(address, entry_point, &calldata).await? {
+                return Ok(result.first().is_some_and(|answer| {
+                    *answer == valid || *answer == FieldElement::from(1u64)
+                }));
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn short_string(value: &str) -> Result<FieldElement, AuthError> {
+    cairo_short_string_to_felt(value)
+        .map_err(|_| AuthError::Config(format!("{} is not a valid short string", value)))
+}
+
+// Felt of a typed data value as wallets encode it: hexadecimal and decimal strings are numbers,
+// other strings are short strings
+fn encode_felt(value: &str) -> Result<FieldElement, AuthError> {
+    if value.starts_with("0x") {
+        FieldElement::from_hex_be(value).map_err(|err| AuthError::Config(err.to_string()))
+    } else if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+        FieldElement::from_dec_str(value).map_err(|err| AuthError::Config(err.to_string()))
+    } else {
+        short_string(value)
+    }
+}
+
+fn domain_hash(chain_id: &str) -> Result<FieldElement, AuthError> {
+    Ok(compute_hash_on_elements(&[
+        starknet_keccak(DOMAIN_TYPE.as_bytes()),
+        encode_felt(DOMAIN_NAME)?,
+        encode_felt(DOMAIN_VERSION)?,
+        encode_felt(chain_id)?,
+    ]))
+}
+
+// Hash of the Login message signed by `address`
+fn message_hash(
+    domain_hash: FieldElement,
+    address: FieldElement,
+    nonce: FieldElement,
+) -> FieldElement {
+    compute_hash_on_elements(&[
+        // "StarkNet Message" fits in a short string
+        cairo_short_string_to_felt(MESSAGE_PREFIX).unwrap(),
+        domain_hash,
+        address,
+        compute_hash_on_elements(&[starknet_keccak(LOGIN_TYPE.as_bytes()), nonce]),
+    ])
+}
+
+// The challenge is too long for a felt, the wallet signs its hash
+fn nonce(challenge: &str) -> FieldElement {
+    starknet_keccak(challenge.as_bytes())
+}
+
+#[derive(Serialize)]
+pub struct Challenge {
+    challenge: String,
+    typed_data: Value,
+}
+
+#[derive(Serialize)]
+pub struct Session {
+    token: String,
+    address: String,
+    expires_at: i64,
+}
+
+pub struct WalletAuth {
+    conf: Auth,
+    tokens: TokenSigner,
+    accounts: Arc<dyn AccountVerifier>,
+    domain_hash: FieldElement,
+}
+
+impl WalletAuth {
+    pub fn new(conf: &Auth, accounts: Arc<dyn AccountVerifier>) -> Result<Self, AuthError> {
+        Ok(WalletAuth {
+            conf: conf.clone(),
+            tokens: TokenSigner::new(&conf.token_key)
+                .map_err(|err| AuthError::Config(err.to_string()))?,
+            accounts,
+            domain_hash: domain_hash(&conf.chain_id)?,
+        })
+    }
+
+    // Typed data for the wallet of `address` to sign, valid for challenge_window seconds
+    pub fn challenge(&self, address: FieldElement, now: i64) -> Challenge {
+        let challenge = self
+            .tokens
+            .sign(CHALLENGE, &format!("{}:{}", to_hex(address), now));
+        let typed_data = json!({
+            "types": {
+                "StarkNetDomain": [
+                    { "name": "name", "type": "felt" },
+                    { "name": "version", "type": "felt" },
+                    { "name": "chainId", "type": "felt" },
+                ],
+                "Login": [{ "name": "nonce", "type": "felt" }],
+            },
+            "primaryType": "Login",
+            "domain": {
+                "name": DOMAIN_NAME,
+                "version": DOMAIN_VERSION,
+                "chainId": self.conf.chain_id,
+            },
+            "message": { "nonce": to_hex(nonce(&challenge)) },
+        });
+        Challenge {
+            challenge,
+            typed_data,
+        }
+    }
+
+    fn verify_challenge(&self, address: FieldElement, challenge: &str, now: i64) -> bool {
+        let Some(subject) = self.tokens.verify(CHALLENGE, challenge) else {
+            return false;
+        };
+        let Some((challenged, issued_at)) = subject.rsplit_once(':') else {
+            return false;
+        };
+        challenged == to_hex(address)
+            && issued_at
+                .parse::<i64>()
+                .is_ok_and(|issued_at| now - issued_at <= self.conf.challenge_window)
+    }
+
+    // Opens a session of session_duration seconds once the account accepted the signature of
+    // the typed data of the challenge
+    pub async fn open_session(
+        &self,
+        address: FieldElement,
+        challenge: &str,
+        signature: &[FieldElement],
+        now: i64,
+    ) -> Result<Session, AuthError> {
+        if !self.verify_challenge(address, challenge, now) {
+            return Err(AuthError::InvalidChallenge);
+        }
+        let hash = message_hash(self.domain_hash, address, nonce(challenge));
+        if !self
+            .accounts
+            .is_valid_signature(address, hash, signature)
+            .await?
+        {
+            return Err(AuthError::InvalidSignature);
+        }
+        let expires_at = now + self.conf.session_duration;
+        let address = to_hex(address);
+        Ok(Session {
+            token: self
+                .tokens
+                .sign(SESSION, &format!("{}:{}", address, expires_at)),
+            address,
+            expires_at,
+        })
+    }
+
+    // Address of a session still open
+    pub fn verify_session(&self, token: &str, now: i64) -> Option<String> {
+        let subject = self.tokens.verify(SESSION, token)?;
+        let (address, expires_at) = subject.rsplit_once(':')?;
+        let expires_at = expires_at.parse::<i64>().ok()?;
+        (now < expires_at).then(|| address.to_string())
+    }
+}
+
+// Extracts the address of the wallet session of the request, wrap it in an Option for the
+// endpoints where a session is optional
+pub struct WalletSession {
+    pub address: String,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for WalletSession {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| {
+                state
+                    .auth
+                    .verify_session(token, chrono::Utc::now().timestamp())
+            })
+            .map(|address| WalletSession { address })
+            .ok_or(ApiError::Unauthorized(
+                "invalid or expired session".to_string(),
+            ))
+    }
+}
+
+#[cfg(test)]
+mod auth_tests {
+    use super::{
+        encode_felt, message_hash, nonce, AccountVerifier, AuthError, RpcAccountVerifier,
+        WalletAuth,
+    };
+    use crate::config::Auth;
+    use axum::async_trait;
+    use serde_json::json;
+    use starknet::core::types::FieldElement;
+    use std::sync::Arc;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn conf() -> Auth {
+        Auth {
+            token_key: "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=".to_string(),
+            rpc_url: String::new(),
+            chain_id: "SN_MAIN".to_string(),
+            challenge_window: 300,
+            session_duration: 3600,
+        }
+    }
+
+    fn address() -> FieldElement {
+        FieldElement::from(0x123u64)
+    }
+
+    // Accepts the signature [hash] of the expected message
+    struct MockAccounts;
+
+    #[async_trait]
+    impl AccountVerifier for MockAccounts {
+        async fn is_valid_signature(
+            &self,
+            _address: FieldElement,
+            hash: FieldElement,
+            signature: &[FieldElement],
+        ) -> Result<bool, AuthError> {
+            Ok(signature == [hash])
+        }
+    }
+
+    fn auth() -> WalletAuth {
+        WalletAuth::new(&conf(), Arc::new(MockAccounts)).unwrap()
+    }
+
+    fn signature(auth: &WalletAuth, challenge: &str) -> Vec<FieldElement> {
+        vec![message_hash(auth.domain_hash, address(), nonce(challenge))]
+    }
+
+    #[test]
+    fn test_felts_are_encoded_like_wallets_do() {
+        assert_eq!(encode_felt("0x1f").unwrap(), FieldElement::from(31u64));
+        assert_eq!(encode_felt("1").unwrap(), FieldElement::from(1u64));
+        assert_eq!(encode_felt("A").unwrap(), FieldElement::from(0x41u64));
+        assert!(encode_felt("a string much longer than thirty one characters").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_signed_challenges_open_a_session() {
+        let auth = auth();
+        let challenge = auth.challenge(address(), NOW).challenge;
+        let session = auth
+            .open_session(
+                address(),
+                &challenge,
+                &signature(&auth, &challenge),
+                NOW + 10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(session.address, "0x0123");
+        assert_eq!(
+            auth.verify_session(&session.token, NOW + 20).as_deref(),
+            Some("0x0123")
+        );
+        assert_eq!(auth.verify_session(&session.token, NOW + 3610), None);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_challenges_and_signatures_are_rejected() {
+        let auth = auth();
+        let challenge = auth.challenge(address(), NOW).challenge;
+        let signature = signature(&auth, &challenge);
+        let other = FieldElement::from(0x456u64);
+        assert!(matches!(
+            auth.open_session(other, &challenge, &signature, NOW).await,
+            Err(AuthError::InvalidChallenge)
+        ));
+        assert!(matches!(
+            auth.open_session(address(), &challenge, &signature, NOW + 301)
+                .await,
+            Err(AuthError::InvalidChallenge)
+        ));
+        assert!(matches!(
+            auth.open_session(address(), &challenge, &[other], NOW)
+                .await,
+            Err(AuthError::InvalidSignature)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_typed_data_embeds_the_challenge() {
+        let challenge = auth().challenge(address(), NOW);
+        assert_eq!(challenge.typed_data["domain"]["chainId"], "SN_MAIN");
+        assert_eq!(
+            FieldElement::from_hex_be(challenge.typed_data["message"]["nonce"].as_str().unwrap())
+                .unwrap(),
+            nonce(&challenge.challenge)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_accounts_are_asked_through_rpc() {
+        let mut server = mockito::Server::new_async().await;
+        let verifier = RpcAccountVerifier::new(&server.url()).unwrap();
+        let hash = FieldElement::from(1u64);
+
+        // SNIP-6 account
+        let mock = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::PartialJson(json!({
+                "method": "starknet_call",
+                "params": { "request": { "calldata": ["0x1", "0x1", "0x2"] } },
+            })))
+            .with_body(json!({ "jsonrpc": "2.0", "id": 1, "result": ["0x56414c4944"] }).to_string())
+            .create_async()
+            .await;
+        assert!(verifier
+            .is_valid_signature(address(), hash, &[FieldElement::from(2u64)])
+            .await
+            .unwrap());
+        mock.remove_async().await;
+
+        // reverted on both entry points
+        let mock = server
+            .mock("POST", "/")
+            .with_body(
+                json!({ "jsonrpc": "2.0", "id": 1, "error": { "code": 40, "message": "Contract error" } })
+                    .to_string(),
+            )
+            .expect(2)
+            .create_async()
+            .await;
+        assert!(!verifier
+            .is_valid_signature(address(), hash, &[])
+            .await
+            .unwrap());
+        mock.assert_async().await;
+        mock.remove_async().await;
+
+        server
+            .mock("POST", "/")
+            .with_status(502)
+            .create_async()
+            .await;
+        assert!(matches!(
+            verifier.is_valid_signature(address(), hash, &[]).await,
+            Err(AuthError::Rpc(_))
+        ));
+    }
+}
@@ -1,57 +1,70 @@
+use sales_common::config::{Database, Encryption, Watchtower};
+use sales_common::email::{ApiVersion, EmailProviderKind};
 use serde::{self, Deserialize};
-use std::env;
-use std::fs;
 
 pub_struct!(Clone, Deserialize; Server { port: u16 });
 
-pub_struct!(Clone, Deserialize; Database {
-    name: String,
-    connection_string: String,
-});
-
 pub_struct!(Clone, Deserialize; Email {
+    provider: EmailProviderKind,
+    api_version: ApiVersion,
     base_url : String,
     api_key: String,
     ar_group_id : String,
 });
 
-pub_struct!(Clone, Deserialize; WatchtowerTypes {
-    info: String,
-    warning: String,
-    severe: String,
+// Accepted values of the metadata submitted by the frontend
+pub_struct!(Clone, Deserialize; Metadata {
+    tax_states: Vec<String>,
+    min_salt_length: usize,
+    verify_sales: bool,
+    verification_window: i64,
+});
+
+// MailerLite group IDs offered to the buyers. The groups of a transaction that wasn't indexed yet
+// are kept unverified, and verified by sale_actions during verification_window seconds.
+pub_struct!(Clone, Deserialize; MailSubscribe {
+    groups: Vec<String>,
+    verify_transactions: bool,
+    verification_window: i64,
+});
+
+// Hex encoded SHA-256 digests of the keys accepted in the Authorization header of the /admin
+// routes, as "Bearer <api_key>"
+pub_struct!(Clone, Deserialize; Admin { api_key_hashes: Vec<String> });
+
+// Key signing the confirmation, unsubscribe and preferences tokens, base64 encoded 32 bytes.
+// Subscribers join the confirmation group until they confirm, during confirmation_window seconds,
+// and preferences tokens are valid for preferences_validity seconds.
+pub_struct!(Clone, Deserialize; Newsletter {
+    token_key: String,
+    confirmation_group_id: String,
+    confirmation_window: i64,
+    preferences_validity: i64,
 });
 
-pub_struct!(Clone, Deserialize; Watchtower {
-    enabled : bool,
-    endpoint: String,
-    app_id: String,
-    token: String,
-    types: WatchtowerTypes,
+// Wallet sessions: key signing the challenges and session tokens, base64 encoded 32 bytes, and
+// the node asked whether account contracts accept a signature. Durations are in seconds.
+pub_struct!(Clone, Deserialize; Auth {
+    token_key: String,
+    rpc_url: String,
+    chain_id: String,
+    challenge_window: i64,
+    session_duration: i64,
 });
 
 pub_struct!(Clone, Deserialize;  Config {
     server: Server,
+    admin: Admin,
+    auth: Auth,
+    newsletter: Newsletter,
+    metadata: Metadata,
+    mail_subscribe: MailSubscribe,
     database: Database,
+    encryption: Encryption,
     watchtower: Watchtower,
     email: Email,
 });
 
 pub fn load() -> Config {
-    let args: Vec<String> = env::args().collect();
-    let config_path = if args.len() <= 1 {
-        "config.toml"
-    } else {
-        args.get(1).unwrap()
-    };
-    let file_contents = fs::read_to_string(config_path);
-    if file_contents.is_err() {
-        panic!("error: unable to read file with path \"{}\"", config_path);
-    }
-
-    match toml::from_str(file_contents.unwrap().as_str()) {
-        Ok(loaded) => loaded,
-        Err(err) => {
-            panic!("error: unable to deserialize config. {}", err);
-        }
-    }
+    sales_common::config::load()
 }
@@ -1,13 +1,24 @@
 use std::sync::Arc;
 
 use crate::{
+    config::Metadata,
+    error::ApiError,
+    extract::JsonBody,
     models::AppState,
-    utils::{get_error, get_specific_error},
+    validation::{self, ValidationErrors},
 };
-use axum::{extract::State, response::IntoResponse, Json};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use mongodb::bson::DateTime;
 use reqwest::StatusCode;
+use sales_common::{
+    metadata_hash::HashVersion,
+    repository::{MetadataDoc, MetadataUpsert},
+};
 use serde_derive::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
 #[derive(Serialize, Deserialize)]
 pub struct AddMetadata {
@@ -15,58 +26,166 @@ pub struct AddMetadata {
     email: String,
     tax_state: String,
     salt: String,
+    // frontends predating the versioning send v1 hashes
+    #[serde(default = "default_hash_version")]
+    hash_version: u32,
 }
 
-fn compute_metadata_hash(email: &str, tax_state: &str, salt: &str) -> String {
-    let separator = "|";
-    let data = format!("{}{}{}{}{}", email, separator, tax_state.replace("|", ""), separator, salt);
+fn default_hash_version() -> u32 {
+    HashVersion::default().into()
+}
 
-    let mut hasher = Sha256::new();
-    hasher.update(data.as_bytes());
-    let result = hasher.finalize();
-    let hash_hex = hex::encode(result);
+impl AddMetadata {
+    fn validate(&self, conf: &Metadata) -> Result<HashVersion, ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        let hash_version = HashVersion::try_from(self.hash_version);
+        errors.check(
+            "hash_version",
+            hash_version.as_ref().map(|_| ()).map_err(|e| e.to_string()),
+        );
+        errors.check("email", validation::email(&self.email));
+        errors.check(
+            "tax_state",
+            validation::one_of(&self.tax_state, &conf.tax_states),
+        );
+        errors.check("salt", validation::hex(&self.salt, conf.min_salt_length));
+        errors.into_result()?;
+        Ok(hash_version.unwrap_or_default())
+    }
+}
 
-    // Truncate the last two characters (8 bits) to make it a 248-bit hash so it fits in a felt
-    let truncated_hash_hex = &hash_hex[0..hash_hex.len() - 2];
-    truncated_hash_hex.to_string()
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataStatus {
+    Created,
+    // the same metadata was already registered, typically a retry of the frontend
+    Replayed,
+    // different metadata was already registered with this meta_hash
+    Conflict,
 }
 
 #[derive(Serialize)]
 pub struct Output {
     success: bool,
+    status: MetadataStatus,
+}
+
+fn upsert_response(upsert: MetadataUpsert) -> Response {
+    let (code, status) = match upsert {
+        MetadataUpsert::Inserted => (StatusCode::OK, MetadataStatus::Created),
+        MetadataUpsert::Replayed => (StatusCode::OK, MetadataStatus::Replayed),
+        MetadataUpsert::Conflict => (StatusCode::CONFLICT, MetadataStatus::Conflict),
+    };
+    let success = code.is_success();
+    (code, Json(Output { success, status })).into_response()
 }
 
 pub async fn handler(
     State(state): State<Arc<AppState>>,
-    Json(query): Json<AddMetadata>,
-) -> impl IntoResponse {
-    let computed_meta_hash = compute_metadata_hash(&query.email, &query.tax_state, &query.salt);
+    JsonBody(query): JsonBody<AddMetadata>,
+) -> Result<Response, ApiError> {
+    let hash_version = query.validate(&state.conf.metadata)?;
+
+    let computed_meta_hash = hash_version.hash(&query.email, &query.tax_state, &query.salt);
     if computed_meta_hash != query.meta_hash {
-        return get_specific_error(StatusCode::BAD_REQUEST, "unable to verify hash".to_string());
+        return Err(ApiError::BadRequest("unable to verify hash".to_string()));
     }
 
-    let metadata_collection = state.db.collection::<mongodb::bson::Document>("metadata");
+    let unverified = if state.conf.metadata.verify_sales {
+        !state
+            .repo
+            .meta_hash_indexed(&query.meta_hash)
+            .await
+            .map_err(|err| {
+                ApiError::internal(&state.logger, "Failed to look for the transaction", err)
+            })?
+    } else {
+        false
+    };
+    let verify_until = unverified.then(|| {
+        let window = state.conf.metadata.verification_window * 1000;
+        DateTime::from_millis(DateTime::now().timestamp_millis() + window)
+    });
 
-    let bson_doc = match mongodb::bson::to_bson(&query) {
-        Ok(bson) => bson,
-        Err(err) => {
-            state.logger.severe(format!("Failed to serialize to BSON: {}", err));
-            return get_error("Internal server error".to_string());
-        }
+    let metadata = MetadataDoc {
+        meta_hash: query.meta_hash,
+        email: query.email,
+        tax_state: query.tax_state,
+        salt: query.salt,
+        email_hash: String::new(),
+        hash_version,
+        unverified,
+        verify_until,
     };
+    let upsert = state
+        .repo
+        .upsert_metadata(&metadata)
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to insert document", err))?;
+    Ok(upsert_response(upsert))
+}
+
+#[cfg(test)]
+mod add_metadata_tests {
+    use super::{upsert_response, AddMetadata};
+    use crate::config::Metadata;
+    use reqwest::StatusCode;
+    use sales_common::{metadata_hash::HashVersion, repository::MetadataUpsert};
 
-    if let mongodb::bson::Bson::Document(document) = bson_doc {
-        match metadata_collection.insert_one(document, None).await {
-            Ok(_) => (),
-            Err(err) => {
-                state.logger.severe(format!("Failed to insert document: {}", err));
-                return get_error("Internal server error".to_string());
-            }
+    fn conf() -> Metadata {
+        Metadata {
+            tax_states: vec!["none".to_string(), "FR".to_string()],
+            min_salt_length: 16,
+            verify_sales: true,
+            verification_window: 3600,
         }
-    } else {
-        state.logger.severe("Failed to create BSON document".to_string());
-        return get_error("Internal server error".to_string());
     }
 
-    (StatusCode::OK, Json(Output { success: true })).into_response()
+    fn query(email: &str, tax_state: &str, salt: &str) -> AddMetadata {
+        AddMetadata {
+            meta_hash: "abc".to_string(),
+            email: email.to_string(),
+            tax_state: tax_state.to_string(),
+            salt: salt.to_string(),
+            hash_version: 1,
+        }
+    }
+
+    #[test]
+    fn test_valid_metadata_is_accepted() {
+        let valid = query("john@example.com", "FR", "0x0123456789abcdef");
+        assert_eq!(valid.validate(&conf()), Ok(HashVersion::V1));
+    }
+
+    #[test]
+    fn test_every_invalid_field_is_reported() {
+        let errors = query("john", "FR|", "").validate(&conf()).unwrap_err();
+        assert_eq!(errors.get("email"), Some("invalid email address"));
+        assert_eq!(errors.get("tax_state"), Some("must be one of none, FR"));
+        assert_eq!(errors.get("salt"), Some("must be an hexadecimal string"));
+    }
+
+    #[test]
+    fn test_unknown_hash_version_is_rejected() {
+        let mut unknown = query("john@example.com", "FR", "0x0123456789abcdef");
+        unknown.hash_version = 42;
+        let errors = unknown.validate(&conf()).unwrap_err();
+        assert_eq!(errors.get("hash_version"), Some("unknown hash version 42"));
+    }
+
+    #[test]
+    fn test_only_conflicts_are_rejected() {
+        assert_eq!(
+            upsert_response(MetadataUpsert::Inserted).status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            upsert_response(MetadataUpsert::Replayed).status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            upsert_response(MetadataUpsert::Conflict).status(),
+            StatusCode::CONFLICT
+        );
+    }
 }
@@ -0,0 +1,23 @@
+use axum::response::Html;
+
+// Page of a link sent by email. Mail scanners and link previews follow links with GET, so the
+// page only asks to confirm the action, its form POSTs to the same URL, token included.
+pub fn render(title: &str, button: &str) -> Html<String> {
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+</head>
+<body>
+<h1>{title}</h1>
+<form method="post">
+<button type="submit">{button}</button>
+</form>
+</body>
+</html>
+"#
+    ))
+}
@@ -1,11 +1,15 @@
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 
 use crate::{
+    config::MailSubscribe,
+    error::ApiError,
+    extract::JsonBody,
     models::AppState,
-    utils::{get_error, to_hex},
+    validation::{self, ValidationErrors},
 };
-use axum::{extract::State, response::IntoResponse, Json};
-use reqwest::StatusCode;
+use axum::{extract::State, Json};
+use mongodb::bson::DateTime;
+use sales_common::{repository::EmailGroupDoc, utils::to_hex};
 use serde_derive::{Deserialize, Serialize};
 use starknet::core::types::FieldElement;
 
@@ -15,58 +19,115 @@ pub struct MailSubscribeQuery {
     groups: Vec<String>,
 }
 
-#[derive(Serialize)]
-pub struct MailSubscribeDoc {
-    #[serde(serialize_with = "field_element_to_hex")]
-    tx_hash: FieldElement,
-    group: String,
-}
-
-fn field_element_to_hex<S>(fe: &FieldElement, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    serializer.serialize_str(&to_hex(*fe))
+impl MailSubscribeQuery {
+    fn validate(&self, conf: &MailSubscribe) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        if self.groups.is_empty() {
+            errors.add("groups", "must not be empty");
+        }
+        for group in &self.groups {
+            errors.check("groups", validation::one_of(group, &conf.groups));
+        }
+        errors.into_result()
+    }
 }
 
 #[derive(Serialize)]
 pub struct Output {
     success: bool,
+    // groups stored by this request
+    added: Vec<String>,
+    // groups this transaction had already joined
+    already_present: Vec<String>,
 }
 
 pub async fn handler(
     State(state): State<Arc<AppState>>,
-    Json(query): Json<MailSubscribeQuery>,
-) -> impl IntoResponse {
-    let emails_collection = state
-        .db
-        .collection::<mongodb::bson::Document>("email_groups");
+    JsonBody(query): JsonBody<MailSubscribeQuery>,
+) -> Result<Json<Output>, ApiError> {
+    let conf = &state.conf.mail_subscribe;
+    query.validate(conf)?;
+    let tx_hash = to_hex(query.tx_hash);
 
-    for group in query.groups {
-        let bson_doc = match mongodb::bson::to_bson(&MailSubscribeDoc {
-            tx_hash: query.tx_hash,
+    // The transaction usually reaches the API before the indexer, its groups are then kept
+    // unverified until it is indexed
+    let unverified = if conf.verify_transactions {
+        !state
+            .repo
+            .transaction_exists(&tx_hash)
+            .await
+            .map_err(|err| {
+                ApiError::internal(&state.logger, "Failed to look for the transaction", err)
+            })?
+    } else {
+        false
+    };
+    let verify_until = unverified.then(|| {
+        let window = conf.verification_window * 1000;
+        DateTime::from_millis(DateTime::now().timestamp_millis() + window)
+    });
+
+    let groups: BTreeSet<String> = query.groups.into_iter().collect();
+    let email_groups: Vec<EmailGroupDoc> = groups
+        .into_iter()
+        .map(|group| EmailGroupDoc {
+            tx_hash: tx_hash.clone(),
             group,
-        }){
-            Ok(bson) => bson,
-            Err(err) => {
-                state.logger.severe(format!("Failed to serialize to BSON: {}", err));
-                return get_error("Internal server error".to_string());
-            }
-        };
+            unverified,
+            verify_until,
+        })
+        .collect();
+    let insert = state
+        .repo
+        .insert_email_groups(&email_groups)
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to insert documents", err))?;
 
-        if let mongodb::bson::Bson::Document(document) = bson_doc {
-            match emails_collection.insert_one(document, None).await {
-                Ok(_) => (),
-                Err(err) => {
-                    state.logger.severe(format!("Failed to insert document: {}", err));
-                    return get_error("Internal server error".to_string());
-                }
-            }
-        } else {
-            state.logger.severe("Failed to create BSON document".to_string());
-            return get_error("Internal server error".to_string());
+    Ok(Json(Output {
+        success: true,
+        added: insert.added,
+        already_present: insert.existing,
+    }))
+}
+
+#[cfg(test)]
+mod mail_subscribe_tests {
+    use super::MailSubscribeQuery;
+    use crate::config::MailSubscribe;
+    use starknet::core::types::FieldElement;
+
+    fn conf() -> MailSubscribe {
+        MailSubscribe {
+            groups: vec!["newsletter".to_string(), "domain_expiry".to_string()],
+            verify_transactions: true,
+            verification_window: 3600,
+        }
+    }
+
+    fn query(groups: &[&str]) -> MailSubscribeQuery {
+        MailSubscribeQuery {
+            tx_hash: FieldElement::from(1u64),
+            groups: groups.iter().map(|group| group.to_string()).collect(),
         }
     }
 
-    (StatusCode::OK, Json(Output { success: true })).into_response()
+    #[test]
+    fn test_offered_groups_are_accepted() {
+        assert!(query(&["domain_expiry", "newsletter"])
+            .validate(&conf())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_unknown_or_missing_groups_are_rejected() {
+        let errors = query(&["newsletter", "admins"])
+            .validate(&conf())
+            .unwrap_err();
+        assert_eq!(
+            errors.get("groups"),
+            Some("must be one of newsletter, domain_expiry")
+        );
+        let errors = query(&[]).validate(&conf()).unwrap_err();
+        assert_eq!(errors.get("groups"), Some("must not be empty"));
+    }
 }
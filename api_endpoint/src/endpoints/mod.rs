@@ -1,3 +1,10 @@
 pub mod add_metadata;
+pub mod link_page;
 pub mod mail_subscribe;
+pub mod newsletter_confirm;
 pub mod newsletter_subscribe;
+pub mod newsletter_unsubscribe;
+pub mod operations;
+pub mod personal_data;
+pub mod preferences;
+pub mod session;
@@ -0,0 +1,201 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    endpoints::{
+        link_page, newsletter_unsubscribe::unsubscribe_token, preferences::preferences_token,
+    },
+    error::ApiError,
+    extract::QueryParams,
+    models::AppState,
+};
+use axum::{extract::State, response::Html, Json};
+use mongodb::bson::DateTime;
+use sales_common::repository::NewsletterStatus;
+use serde_derive::{Deserialize, Serialize};
+
+// Purpose of the signed tokens of the confirmation links
+const CONFIRM: &str = "newsletter_confirm";
+
+// The token expires with the confirmation window of the subscription, `confirm_until`
+pub fn confirm_token(state: &AppState, email: &str, confirm_until: DateTime) -> String {
+    state.tokens.sign_until(
+        CONFIRM,
+        &state.repo.cipher().blind_index(email),
+        confirm_until.timestamp_millis() / 1000,
+    )
+}
+
+fn email_hash(state: &AppState, token: &str) -> Result<String, ApiError> {
+    let now = chrono::Utc::now().timestamp();
+    state
+        .tokens
+        .verify_until(CONFIRM, token, now)
+        .ok_or_else(|| ApiError::BadRequest("invalid or expired token".to_string()))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfirmQuery {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    success: bool,
+}
+
+// Opening the link only shows a page whose button confirms
+pub async fn page_handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<ConfirmQuery>,
+) -> Result<Html<String>, ApiError> {
+    email_hash(&state, &query.token)?;
+    Ok(link_page::render(
+        "Confirm your newsletter subscription",
+        "Confirm",
+    ))
+}
+
+// Activates a pending subscription, confirming it again succeeds
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<ConfirmQuery>,
+) -> Result<Json<Output>, ApiError> {
+    let email_hash = email_hash(&state, &query.token)?;
+
+    let newsletter = state
+        .repo
+        .find_newsletter_by_hash(&email_hash)
+        .await
+        .map_err(|err| {
+            ApiError::internal(&state.logger, "Failed to find newsletter subscription", err)
+        })?;
+    let newsletter = match newsletter {
+        Some(newsletter) if newsletter.status == NewsletterStatus::Active => {
+            return Ok(Json(Output { success: true }));
+        }
+        Some(newsletter) if newsletter.confirm_until > Some(DateTime::now()) => newsletter,
+        _ => {
+            return Err(ApiError::NotFound(
+                "unknown or expired subscription".to_string(),
+            ))
+        }
+    };
+
+    // Move the subscriber from the confirmation group to the newsletter group, along with the
+    // tokens of its unsubscribe and preference center links
+    let fields = BTreeMap::from([
+        (
+            "unsubscribe_token".to_string(),
+            unsubscribe_token(&state, &newsletter.email),
+        ),
+        (
+            "preferences_token".to_string(),
+            preferences_token(&state, &newsletter.email),
+        ),
+    ]);
+    state
+        .email
+        .upsert_subscriber(
+            &newsletter.email,
+            &fields,
+            std::slice::from_ref(&state.conf.email.ar_group_id),
+        )
+        .await
+        .map_err(|err| ApiError::email_provider(&state.logger, err))?;
+    let group = &state.conf.newsletter.confirmation_group_id;
+    let removed = match state.email.find_subscriber(&newsletter.email).await {
+        Ok(Some(subscriber)) => state.email.remove_from_group(&subscriber.id, group).await,
+        Ok(None) => Ok(()),
+        Err(err) => Err(err),
+    };
+    // the subscription is active either way
+    if let Err(err) = removed {
+        state.logger.warning(format!(
+            "Failed to remove subscriber from the confirmation group: {}",
+            err
+        ));
+    }
+
+    state
+        .repo
+        .confirm_newsletter(&email_hash)
+        .await
+        .map_err(|err| {
+            ApiError::internal(
+                &state.logger,
+                "Failed to confirm newsletter subscription",
+                err,
+            )
+        })?;
+
+    Ok(Json(Output { success: true }))
+}
+
+#[cfg(test)]
+mod newsletter_confirm_tests {
+    use super::{confirm_token, handler, page_handler, ConfirmQuery};
+    use crate::{
+        endpoints::newsletter_subscribe::{self, AddNewsletterQuery},
+        error::ApiError,
+        extract::{JsonBody, QueryParams},
+        test_utils::{state, test_db},
+    };
+    use axum::extract::State;
+    use mongodb::bson::DateTime;
+    use sales_common::{
+        email::InMemoryEmailProvider,
+        repository::{migrations, NewsletterStatus},
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn query(token: &str) -> QueryParams<ConfirmQuery> {
+        QueryParams(ConfirmQuery {
+            token: token.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_opening_the_link_only_confirms_once_posted() {
+        let Some(db) = test_db("confirm_page").await else {
+            return;
+        };
+        let provider = Arc::new(InMemoryEmailProvider::default());
+        let state = state(&db, provider.clone());
+        migrations::run(&state.repo).await.unwrap();
+        let email = "john@example.com";
+        let subscription: AddNewsletterQuery =
+            serde_json::from_value(json!({ "email": email })).unwrap();
+        let subscribed =
+            newsletter_subscribe::handler(State(state.clone()), None, JsonBody(subscription)).await;
+        assert!(subscribed.is_ok());
+        let token = provider.subscribers()[0].fields["confirm_token"].clone();
+        let status = || async {
+            let newsletter = state.repo.find_newsletter(email).await.unwrap();
+            newsletter.unwrap().status
+        };
+
+        let page = page_handler(State(state.clone()), query(&token))
+            .await
+            .unwrap();
+        assert!(page.0.contains(r#"<form method="post">"#));
+        assert_eq!(status().await, NewsletterStatus::Pending);
+
+        // tokens expire with the confirmation window
+        let expired = confirm_token(&state, email, DateTime::from_millis(0));
+        assert_eq!(
+            handler(State(state.clone()), query(&expired)).await.err(),
+            Some(ApiError::BadRequest("invalid or expired token".to_string()))
+        );
+        assert_eq!(status().await, NewsletterStatus::Pending);
+
+        assert!(
+            handler(State(state.clone()), query(&token))
+                .await
+                .unwrap()
+                .success
+        );
+        assert_eq!(status().await, NewsletterStatus::Active);
+        db.drop(None).await.unwrap();
+    }
+}
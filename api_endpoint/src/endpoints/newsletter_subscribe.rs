@@ -1,9 +1,24 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
-use crate::{models::AppState, utils::get_error};
-use axum::{extract::State, response::IntoResponse, Json};
-use reqwest::StatusCode;
+use crate::{
+    auth::WalletSession,
+    endpoints::newsletter_confirm::confirm_token,
+    error::ApiError,
+    extract::JsonBody,
+    models::AppState,
+    validation::{self, ValidationErrors},
+};
+use axum::{extract::State, Json};
+use mongodb::bson::DateTime;
+use sales_common::{
+    email::EmailError,
+    logger::Logger,
+    repository::{is_duplicate_key, NewsletterDoc, NewsletterStatus, RepositoryError},
+    utils::to_hex,
+};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use starknet::core::types::FieldElement;
 
 #[derive(Serialize, Deserialize)]
 pub struct AddNewsletterQuery {
@@ -11,80 +26,316 @@ pub struct AddNewsletterQuery {
     address: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct AddNewsletterRecord {
-    email: String,
-    address: Option<String>,
-    source: String,
-}
-
 #[derive(Serialize)]
 pub struct Output {
     success: bool,
+    // null when the address was not linked, for lack of a wallet session of this address
+    linked_address: Option<String>,
+}
+
+fn already_exists() -> ApiError {
+    ApiError::Conflict("Email already exists".to_string())
+}
+
+// MailerLite explains its rejections like {"message": "...", "errors": {"email": ["..."]}}
+fn rejection_message(body: &str) -> String {
+    serde_json::from_str::<Value>(body)
+        .ok()
+        .and_then(|body| {
+            body["errors"]["email"][0]
+                .as_str()
+                .or(body["message"].as_str())
+                .map(str::to_string)
+        })
+        .unwrap_or("rejected by the email provider".to_string())
+}
+
+// Emails rejected by the provider are reported like our own validation errors
+fn provider_error(logger: &Logger, err: EmailError) -> ApiError {
+    match err {
+        EmailError::Status { status: 422, body } => {
+            let mut errors = ValidationErrors::default();
+            errors.add("email", rejection_message(&body));
+            errors.into()
+        }
+        err => ApiError::email_provider(logger, err),
+    }
+}
+
+// An address is only linked to the email by a wallet session of this address. Clients sending
+// an address without its session still subscribe, `linked_address` tells them it was left out.
+fn verified_address(
+    address: Option<&str>,
+    session: Option<&WalletSession>,
+) -> Result<Option<String>, ValidationErrors> {
+    let Some(address) = address else {
+        return Ok(None);
+    };
+    let Ok(address) = FieldElement::from_hex_be(address).map(to_hex) else {
+        let mut errors = ValidationErrors::default();
+        errors.add("address", "must be a starknet address");
+        return Err(errors);
+    };
+    Ok(session
+        .filter(|session| session.address == address)
+        .map(|_| address))
 }
 
 pub async fn handler(
     State(state): State<Arc<AppState>>,
-    Json(query): Json<AddNewsletterQuery>,
-) -> impl IntoResponse {
-    let collection = state.db.collection::<mongodb::bson::Document>("newsletter");
+    session: Option<WalletSession>,
+    JsonBody(query): JsonBody<AddNewsletterQuery>,
+) -> Result<Json<Output>, ApiError> {
+    let mut errors = ValidationErrors::default();
+    errors.check("email", validation::email(&query.email));
+    errors.into_result()?;
+    let address = verified_address(query.address.as_deref(), session.as_ref())?;
 
     // Check if email already exists
-    let filter = mongodb::bson::doc! { "email": &query.email };
-    let result = match collection
-        .find_one(filter, None)
-        .await {
-        Ok(res) => res,
-        Err(err) => {
-            state.logger.severe(format!("Failed to execute find_one: {}", err));
-            return get_error("Internal server error".to_string());
+    let result = state
+        .repo
+        .find_newsletter(&query.email)
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to execute find_one", err))?;
+
+    // A pending subscription is replaced, which restarts its confirmation window
+    match result {
+        Some(newsletter) if newsletter.status == NewsletterStatus::Active => {
+            return Err(already_exists());
+        }
+        Some(newsletter) => {
+            state
+                .repo
+                .delete_newsletter_by_hash(&newsletter.email_hash)
+                .await
+                .map_err(|err| {
+                    ApiError::internal(&state.logger, "Failed to replace pending subscription", err)
+                })?;
         }
+        None => (),
+    }
+
+    // Add the email to the confirmation group of the email provider, whose automation mails the
+    // link confirming the subscription. The subscription is only stored once this succeeded.
+    let window = state.conf.newsletter.confirmation_window * 1000;
+    let confirm_until = DateTime::from_millis(DateTime::now().timestamp_millis() + window);
+    let fields = BTreeMap::from([(
+        "confirm_token".to_string(),
+        confirm_token(&state, &query.email, confirm_until),
+    )]);
+    state
+        .email
+        .upsert_subscriber(
+            &query.email,
+            &fields,
+            std::slice::from_ref(&state.conf.newsletter.confirmation_group_id),
+        )
+        .await
+        .map_err(|err| provider_error(&state.logger, err))?;
+
+    let newsletter = NewsletterDoc {
+        email: query.email,
+        email_hash: String::new(),
+        address: address.clone(),
+        source: "newsletter_subscription".to_string(),
+        status: NewsletterStatus::Pending,
+        confirm_until: Some(confirm_until),
     };
+    match state.repo.insert_newsletter(&newsletter).await {
+        Ok(()) => Ok(Json(Output {
+            success: true,
+            linked_address: address,
+        })),
+        // subscribed concurrently
+        Err(RepositoryError::Database(err)) if is_duplicate_key(&err) => Err(already_exists()),
+        Err(err) => Err(ApiError::internal(
+            &state.logger,
+            "Failed to insert document",
+            err,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod newsletter_subscribe_tests {
+    use super::{handler, provider_error, verified_address, AddNewsletterQuery};
+    use crate::{
+        auth::WalletSession,
+        error::ApiError,
+        extract::JsonBody,
+        test_utils::{state, test_db},
+        validation::ValidationErrors,
+    };
+    use axum::extract::State;
+    use sales_common::{
+        config::{Watchtower, WatchtowerTypes},
+        email::{ApiVersion, EmailError, EmailProvider, InMemoryEmailProvider, MailerLite},
+        logger::Logger,
+        repository::migrations,
+    };
+    use serde_json::json;
+    use std::{collections::BTreeMap, sync::Arc};
+
+    fn logger() -> Logger {
+        Logger::new(&Watchtower {
+            enabled: false,
+            endpoint: String::new(),
+            app_id: String::new(),
+            token: String::new(),
+            types: WatchtowerTypes {
+                info: String::new(),
+                warning: String::new(),
+                severe: String::new(),
+            },
+        })
+    }
 
-    if let Some(_) = result {
-        return get_error("Email already exists".to_string());
+    // Error of a subscription against a mock MailerLite answering with `status` and `body`
+    async fn subscription_error(status: usize, headers: &[(&str, &str)], body: &str) -> EmailError {
+        let mut server = mockito::Server::new_async().await;
+        let mut mock = server
+            .mock("POST", "/api/subscribers")
+            .with_status(status)
+            .with_body(body);
+        for (name, value) in headers {
+            mock = mock.with_header(*name, value);
+        }
+        mock.create_async().await;
+        MailerLite::new(ApiVersion::Connect, &format!("{}/api", server.url()), "key")
+            .upsert_subscriber("john@example", &BTreeMap::new(), &[])
+            .await
+            .unwrap_err()
     }
 
-    // Mailerlite API
-    let base_url = state.conf.email.base_url.clone();
-    let api_key = state.conf.email.api_key.clone();
-    let ar_group_id = state.conf.email.ar_group_id.clone();
-
-    let url = format!("{}/subscribers", base_url);
-    let client = reqwest::Client::new();
-    let response = client
-        .post(&url)
-        .header("content-type", "application/json")
-        .header("accept", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&serde_json::json!({ "email": query.email, "groups": [ar_group_id] }))
-        .send()
+    #[tokio::test]
+    async fn test_rejected_emails_are_validation_errors() {
+        let err = subscription_error(
+            422,
+            &[],
+            &json!({
+                "message": "The given data was invalid.",
+                "errors": { "email": ["The email must be a valid email address."] }
+            })
+            .to_string(),
+        )
         .await;
+        let mut errors = ValidationErrors::default();
+        errors.add("email", "The email must be a valid email address.");
+        assert_eq!(provider_error(&logger(), err), ApiError::Validation(errors));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_are_retried_later() {
+        let err = subscription_error(429, &[("retry-after", "30")], "").await;
+        assert_eq!(
+            provider_error(&logger(), err),
+            ApiError::Unavailable { retry_after: 30 }
+        );
 
-    if let Err(err) = response {
-        return get_error(format!("Failed to send request to Mailerlite: {}", err));
+        let err = subscription_error(429, &[], "").await;
+        assert_eq!(
+            provider_error(&logger(), err),
+            ApiError::Unavailable { retry_after: 60 }
+        );
     }
 
-    let bson_doc = match mongodb::bson::to_bson(&AddNewsletterRecord {
-        email: query.email,
-        address: query.address,
-        source: "newsletter_subscription".to_string(),
-    }) {
-        Ok(bson) => bson,
-        Err(err) => {
-            state.logger.severe(format!("Failed to serialize to BSON: {}", err));
-            return get_error("Internal server error".to_string());
-        }
-    };
+    #[tokio::test]
+    async fn test_other_failures_are_not_disclosed() {
+        let err = subscription_error(401, &[], "Unauthenticated.").await;
+        assert_eq!(provider_error(&logger(), err), ApiError::Internal);
+    }
 
-    if let mongodb::bson::Bson::Document(document) = bson_doc {
-        match collection.insert_one(document, None).await {
-            Ok(_) => (),
-            Err(err) => return get_error(format!("Failed to insert document: {}", err)),
-        }
-    } else {
-        return get_error("Failed to create BSON document".to_string());
+    #[test]
+    fn test_addresses_without_a_session_of_the_address_are_dropped() {
+        let session = WalletSession {
+            address: "0x0123".to_string(),
+        };
+        assert_eq!(verified_address(None, None), Ok(None));
+        assert_eq!(
+            verified_address(Some("0x123"), Some(&session)),
+            Ok(Some("0x0123".to_string()))
+        );
+        assert_eq!(verified_address(Some("0x456"), Some(&session)), Ok(None));
+        assert_eq!(verified_address(Some("0x123"), None), Ok(None));
+        let errors = verified_address(Some("john"), Some(&session)).unwrap_err();
+        assert_eq!(errors.get("address"), Some("must be a starknet address"));
+    }
+
+    fn subscription(email: &str, address: &str) -> JsonBody<AddNewsletterQuery> {
+        JsonBody(AddNewsletterQuery {
+            email: email.to_string(),
+            address: Some(address.to_string()),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_malformed_addresses_are_rejected() {
+        let Some(db) = test_db("subscribe_malformed_address").await else {
+            return;
+        };
+        let provider = Arc::new(InMemoryEmailProvider::default());
+        let state = state(&db, provider.clone());
+        migrations::run(&state.repo).await.unwrap();
+
+        let result = handler(
+            State(state.clone()),
+            None,
+            subscription("john@example.com", "john"),
+        )
+        .await;
+        let Err(ApiError::Validation(errors)) = result else {
+            panic!("the address was accepted");
+        };
+        assert_eq!(errors.get("address"), Some("must be a starknet address"));
+        assert!(provider.subscribers().is_empty());
+        assert!(state
+            .repo
+            .find_newsletter("john@example.com")
+            .await
+            .unwrap()
+            .is_none());
+        db.drop(None).await.unwrap();
     }
 
-    (StatusCode::OK, Json(Output { success: true })).into_response()
+    #[tokio::test]
+    async fn test_only_addresses_of_the_session_are_linked() {
+        let Some(db) = test_db("subscribe_linked_address").await else {
+            return;
+        };
+        let state = state(&db, Arc::new(InMemoryEmailProvider::default()));
+        migrations::run(&state.repo).await.unwrap();
+        let session = || {
+            Some(WalletSession {
+                address: "0x0123".to_string(),
+            })
+        };
+
+        // subscribed, but without the address of another wallet
+        let output = handler(
+            State(state.clone()),
+            session(),
+            subscription("john@example.com", "0x456"),
+        )
+        .await
+        .unwrap();
+        assert!(output.success);
+        assert_eq!(output.linked_address, None);
+        let newsletter = state.repo.find_newsletter("john@example.com").await;
+        assert_eq!(newsletter.unwrap().unwrap().address, None);
+
+        let output = handler(
+            State(state.clone()),
+            session(),
+            subscription("jane@example.com", "0x123"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.linked_address, Some("0x0123".to_string()));
+        let newsletter = state.repo.find_newsletter("jane@example.com").await;
+        assert_eq!(
+            newsletter.unwrap().unwrap().address,
+            Some("0x0123".to_string())
+        );
+        db.drop(None).await.unwrap();
+    }
 }
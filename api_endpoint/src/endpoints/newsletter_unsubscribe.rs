@@ -0,0 +1,159 @@
+use std::sync::Arc;
+
+use crate::{endpoints::link_page, error::ApiError, extract::QueryParams, models::AppState};
+use axum::{extract::State, response::Html, Json};
+use sales_common::repository::NewsletterStatus;
+use serde_derive::{Deserialize, Serialize};
+
+// Purpose of the signed tokens of the unsubscribe links
+const UNSUBSCRIBE: &str = "newsletter_unsubscribe";
+
+// The token identifies the subscription through the keyed hash of its email, so the email
+// doesn't appear in the link
+pub fn unsubscribe_token(state: &AppState, email: &str) -> String {
+    state
+        .tokens
+        .sign(UNSUBSCRIBE, &state.repo.cipher().blind_index(email))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnsubscribeQuery {
+    token: String,
+}
+
+#[derive(Serialize)]
+pub struct Output {
+    success: bool,
+}
+
+fn email_hash(state: &AppState, token: &str) -> Result<String, ApiError> {
+    state
+        .tokens
+        .verify(UNSUBSCRIBE, token)
+        .ok_or_else(|| ApiError::BadRequest("invalid token".to_string()))
+}
+
+// Opening the link only shows a page whose button unsubscribes
+pub async fn page_handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<UnsubscribeQuery>,
+) -> Result<Html<String>, ApiError> {
+    email_hash(&state, &query.token)?;
+    Ok(link_page::render(
+        "Unsubscribe from the newsletter",
+        "Unsubscribe",
+    ))
+}
+
+// Answers the page and one-click unsubscribes (RFC 8058). Unsubscribing twice succeeds, the
+// second time there is nothing left to remove.
+pub async fn handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<UnsubscribeQuery>,
+) -> Result<Json<Output>, ApiError> {
+    let email_hash = email_hash(&state, &query.token)?;
+
+    let newsletter = state
+        .repo
+        .find_newsletter_by_hash(&email_hash)
+        .await
+        .map_err(|err| {
+            ApiError::internal(&state.logger, "Failed to find newsletter subscription", err)
+        })?;
+
+    // The provider first, so the subscription is kept to retry if it fails
+    if let Some(newsletter) = newsletter {
+        let group = match newsletter.status {
+            NewsletterStatus::Active => &state.conf.email.ar_group_id,
+            NewsletterStatus::Pending => &state.conf.newsletter.confirmation_group_id,
+        };
+        let removed = match state.email.find_subscriber(&newsletter.email).await {
+            Ok(Some(subscriber)) => state.email.remove_from_group(&subscriber.id, group).await,
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        removed.map_err(|err| ApiError::email_provider(&state.logger, err))?;
+        state
+            .repo
+            .delete_newsletter_by_hash(&email_hash)
+            .await
+            .map_err(|err| {
+                ApiError::internal(
+                    &state.logger,
+                    "Failed to delete newsletter subscription",
+                    err,
+                )
+            })?;
+    }
+
+    Ok(Json(Output { success: true }))
+}
+
+#[cfg(test)]
+mod newsletter_unsubscribe_tests {
+    use super::{handler, page_handler, unsubscribe_token, UnsubscribeQuery};
+    use crate::{
+        error::ApiError,
+        extract::QueryParams,
+        test_utils::{state, test_db},
+    };
+    use axum::extract::State;
+    use sales_common::{
+        email::{EmailProvider, InMemoryEmailProvider},
+        repository::{migrations, NewsletterDoc, NewsletterStatus},
+    };
+    use std::{collections::BTreeMap, sync::Arc};
+
+    fn query(token: &str) -> QueryParams<UnsubscribeQuery> {
+        QueryParams(UnsubscribeQuery {
+            token: token.to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_opening_the_link_only_unsubscribes_once_posted() {
+        let Some(db) = test_db("unsubscribe_page").await else {
+            return;
+        };
+        let provider = Arc::new(InMemoryEmailProvider::default());
+        let state = state(&db, provider.clone());
+        migrations::run(&state.repo).await.unwrap();
+        let email = "john@example.com";
+        state
+            .repo
+            .insert_newsletter(&NewsletterDoc {
+                email: email.to_string(),
+                email_hash: String::new(),
+                address: None,
+                source: "newsletter_subscription".to_string(),
+                status: NewsletterStatus::Active,
+                confirm_until: None,
+            })
+            .await
+            .unwrap();
+        provider
+            .upsert_subscriber(email, &BTreeMap::new(), &["ar_group".to_string()])
+            .await
+            .unwrap();
+        let token = unsubscribe_token(&state, email);
+
+        assert_eq!(
+            page_handler(State(state.clone()), query("john.xxx"))
+                .await
+                .unwrap_err(),
+            ApiError::BadRequest("invalid token".to_string())
+        );
+        let page = page_handler(State(state.clone()), query(&token))
+            .await
+            .unwrap();
+        assert!(page.0.contains(r#"<form method="post">"#));
+        assert!(state.repo.find_newsletter(email).await.unwrap().is_some());
+        assert_eq!(provider.subscribers()[0].groups, vec!["ar_group"]);
+
+        let output = handler(State(state.clone()), query(&token)).await.unwrap();
+        assert!(output.success);
+        assert!(state.repo.find_newsletter(email).await.unwrap().is_none());
+        assert!(provider.subscribers()[0].groups.is_empty());
+        db.drop(None).await.unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use std::sync::Arc;
+
+use crate::{
+    error::ApiError,
+    extract::{JsonBody, QueryParams},
+    models::AppState,
+    validation::{self, ValidationErrors},
+};
+use axum::{extract::State, Json};
+use mongodb::bson::DateTime;
+use sales_common::repository::{IndexedSaleDoc, NewsletterDoc, NotificationDoc, NotificationKind};
+use serde_derive::{Deserialize, Serialize};
+
+// Operations on the sales and their notifications, served under /admin. Lists are paginated with
+// `offset` and `limit`, the response gives the `next_offset` while there are more.
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+fn default_limit() -> i64 {
+    DEFAULT_LIMIT
+}
+
+fn check_limit(errors: &mut ValidationErrors, limit: i64) {
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        errors.add("limit", format!("must be between 1 and {}", MAX_LIMIT));
+    }
+}
+
+// Offset of the next page, when more than `limit` items were fetched
+fn next_page<T>(items: &mut Vec<T>, offset: u64, limit: i64) -> Option<u64> {
+    let more = items.len() as i64 > limit;
+    items.truncate(limit as usize);
+    more.then_some(offset + limit as u64)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SalesQuery {
+    domain: Option<String>,
+    tx_hash: Option<String>,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default = "default_limit")]
+    limit: i64,
+}
+
+#[derive(Serialize)]
+pub struct SalesOutput {
+    sales: Vec<IndexedSaleDoc>,
+    next_offset: Option<u64>,
+}
+
+// A sale or renewal, identified like in the processed ledgers and the outbox
+#[derive(Serialize, Deserialize)]
+pub struct SaleQuery {
+    tx_hash: String,
+    domain: String,
+}
+
+impl SaleQuery {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("tx_hash", validation::hex(&self.tx_hash, 1));
+        if self.domain.is_empty() {
+            errors.add("domain", "must not be empty");
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NotificationQuery {
+    kind: NotificationKind,
+    tx_hash: String,
+    domain: String,
+}
+
+impl NotificationQuery {
+    fn sale(&self) -> SaleQuery {
+        SaleQuery {
+            tx_hash: self.tx_hash.clone(),
+            domain: self.domain.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct NotificationStatus {
+    kind: NotificationKind,
+    // when sale_actions added the notification to the outbox
+    processed_at: Option<DateTime>,
+    notification: Option<NotificationDoc>,
+}
+
+#[derive(Serialize)]
+pub struct NotificationsOutput {
+    notifications: Vec<NotificationStatus>,
+}
+
+#[derive(Serialize)]
+pub struct SuccessOutput {
+    success: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SubscribersQuery {
+    #[serde(default)]
+    offset: u64,
+    #[serde(default = "default_limit")]
+    limit: i64,
+}
+
+#[derive(Serialize)]
+pub struct SubscribersOutput {
+    subscribers: Vec<NewsletterDoc>,
+    total: u64,
+    next_offset: Option<u64>,
+}
+
+// Indexed sales of a domain and/or transaction, the latest first
+pub async fn sales_handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<SalesQuery>,
+) -> Result<Json<SalesOutput>, ApiError> {
+    let mut errors = ValidationErrors::default();
+    check_limit(&mut errors, query.limit);
+    errors.into_result()?;
+
+    let mut sales = state
+        .repo
+        .search_sales(
+            query.domain.as_deref(),
+            query.tx_hash.as_deref(),
+            query.offset,
+            query.limit + 1,
+        )
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to search sales", err))?;
+    let next_offset = next_page(&mut sales, query.offset, query.limit);
+    Ok(Json(SalesOutput { sales, next_offset }))
+}
+
+// Whether the purchase and renewal notifications of a sale were processed, and their delivery
+pub async fn notifications_handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<SaleQuery>,
+) -> Result<Json<NotificationsOutput>, ApiError> {
+    query.validate()?;
+    let internal = |err| ApiError::internal(&state.logger, "Failed to find notifications", err);
+
+    let mut found = state
+        .repo
+        .find_notifications(&query.tx_hash, &query.domain)
+        .await
+        .map_err(internal)?;
+    let mut notifications = Vec::new();
+    for kind in [NotificationKind::Purchase, NotificationKind::Renewal] {
+        let processed = state
+            .repo
+            .find_processed(kind.ledger(), &query.tx_hash, &query.domain)
+            .await
+            .map_err(internal)?;
+        let notification = found
+            .iter()
+            .position(|notification| notification.kind == kind)
+            .map(|index| found.remove(index));
+        if processed.is_some() || notification.is_some() {
+            notifications.push(NotificationStatus {
+                kind,
+                processed_at: processed.map(|processed| processed.processed_at),
+                notification,
+            });
+        }
+    }
+    Ok(Json(NotificationsOutput { notifications }))
+}
+
+// Sends a failed or dead notification again on the next delivery of sale_actions with fresh
+// attempts. Sent notifications no longer have their request, purging their processed marker
+// builds it again.
+pub async fn resend_handler(
+    State(state): State<Arc<AppState>>,
+    JsonBody(query): JsonBody<NotificationQuery>,
+) -> Result<Json<SuccessOutput>, ApiError> {
+    query.sale().validate()?;
+    let internal = |err| ApiError::internal(&state.logger, "Failed to resend notification", err);
+    let found = state
+        .repo
+        .resend_notification(query.kind, &query.tx_hash, &query.domain)
+        .await
+        .map_err(internal)?;
+    if !found {
+        let notifications = state
+            .repo
+            .find_notifications(&query.tx_hash, &query.domain)
+            .await
+            .map_err(internal)?;
+        if notifications
+            .iter()
+            .any(|notification| notification.kind == query.kind)
+        {
+            return Err(ApiError::Conflict(
+                "the request of this notification was cleared, purge its processed marker instead"
+                    .to_string(),
+            ));
+        }
+        return Err(ApiError::NotFound("unknown notification".to_string()));
+    }
+    state.logger.info(format!(
+        "admin: resending {:?} notification of {} in {}",
+        query.kind, query.domain, query.tx_hash
+    ));
+    Ok(Json(SuccessOutput { success: true }))
+}
+
+// Lets sale_actions process a sale or renewal again, building a new notification from its
+// current metadata
+pub async fn purge_handler(
+    State(state): State<Arc<AppState>>,
+    JsonBody(query): JsonBody<NotificationQuery>,
+) -> Result<Json<SuccessOutput>, ApiError> {
+    query.sale().validate()?;
+    let purged = state
+        .repo
+        .purge_processed(query.kind, &query.tx_hash, &query.domain)
+        .await
+        .map_err(|err| {
+            ApiError::internal(&state.logger, "Failed to purge processed marker", err)
+        })?;
+    if !purged {
+        return Err(ApiError::NotFound("not processed".to_string()));
+    }
+    state.logger.info(format!(
+        "admin: purged {:?} processed marker of {} in {}",
+        query.kind, query.domain, query.tx_hash
+    ));
+    Ok(Json(SuccessOutput { success: true }))
+}
+
+// Newsletter subscriptions, decrypted, in the order they were stored
+pub async fn subscribers_handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<SubscribersQuery>,
+) -> Result<Json<SubscribersOutput>, ApiError> {
+    let mut errors = ValidationErrors::default();
+    check_limit(&mut errors, query.limit);
+    errors.into_result()?;
+
+    let total = state
+        .repo
+        .count_newsletters()
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to count subscribers", err))?;
+    let mut subscribers = state
+        .repo
+        .list_newsletters(query.offset, query.limit + 1)
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to list subscribers", err))?;
+    let next_offset = next_page(&mut subscribers, query.offset, query.limit);
+    Ok(Json(SubscribersOutput {
+        subscribers,
+        total,
+        next_offset,
+    }))
+}
+
+#[cfg(test)]
+mod operations_tests {
+    use super::{check_limit, next_page, SaleQuery, MAX_LIMIT};
+    use crate::validation::ValidationErrors;
+
+    #[test]
+    fn test_next_page_is_given_while_there_are_more() {
+        let mut items = vec![1, 2, 3];
+        assert_eq!(next_page(&mut items, 10, 2), Some(12));
+        assert_eq!(items, vec![1, 2]);
+        let mut items = vec![1, 2];
+        assert_eq!(next_page(&mut items, 10, 2), None);
+        assert_eq!(items, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_queries_are_validated() {
+        let mut errors = ValidationErrors::default();
+        check_limit(&mut errors, MAX_LIMIT);
+        assert!(errors.into_result().is_ok());
+        let mut errors = ValidationErrors::default();
+        check_limit(&mut errors, 0);
+        assert_eq!(errors.get("limit"), Some("must be between 1 and 500"));
+
+        let query = |tx_hash: &str, domain: &str| SaleQuery {
+            tx_hash: tx_hash.to_string(),
+            domain: domain.to_string(),
+        };
+        assert!(query("0x1", "john.stark").validate().is_ok());
+        let errors = query("john", "").validate().unwrap_err();
+        assert_eq!(errors.get("tx_hash"), Some("must be an hexadecimal string"));
+        assert_eq!(errors.get("domain"), Some("must not be empty"));
+    }
+}
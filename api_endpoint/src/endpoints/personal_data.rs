@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use crate::{
+    error::ApiError,
+    extract::JsonBody,
+    models::AppState,
+    validation::{self, ValidationErrors},
+};
+use axum::{extract::State, Json};
+use mongodb::bson::DateTime;
+use sales_common::{
+    email::Subscriber,
+    repository::{DataRequestAction, DataRequestDoc, ErasedData, PersonalData},
+};
+use serde_derive::{Deserialize, Serialize};
+
+// Data subject requests of the GDPR, served under /admin. Every request is recorded in the
+// `data_requests` collection with the keyed hash of the email.
+
+#[derive(Serialize, Deserialize)]
+pub struct DataSubjectQuery {
+    email: String,
+}
+
+impl DataSubjectQuery {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        errors.check("email", validation::email(&self.email));
+        errors.into_result()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExportOutput {
+    data: PersonalData,
+    subscriber: Option<Subscriber>,
+}
+
+#[derive(Serialize)]
+pub struct EraseOutput {
+    success: bool,
+    erased: ErasedData,
+    subscriber_forgotten: bool,
+}
+
+async fn record(
+    state: &AppState,
+    action: DataRequestAction,
+    email: &str,
+    result: Result<Option<ErasedData>, String>,
+) {
+    let (erased, error) = match result {
+        Ok(erased) => (erased, None),
+        Err(error) => (None, Some(error)),
+    };
+    let request = DataRequestDoc {
+        action,
+        email_hash: state.repo.cipher().blind_index(email),
+        requested_at: DateTime::now(),
+        erased,
+        error,
+    };
+    if let Err(err) = state.repo.insert_data_request(&request).await {
+        state
+            .logger
+            .severe(format!("Failed to record data request: {}", err));
+    }
+}
+
+async fn failure(
+    state: &AppState,
+    action: DataRequestAction,
+    email: &str,
+    error: String,
+) -> ApiError {
+    let context = format!("Failed to process {:?} data request", action);
+    let api_error = ApiError::internal(&state.logger, &context, &error);
+    record(state, action, email, Err(error)).await;
+    api_error
+}
+
+// Everything stored about an email, in our database and by the email provider
+pub async fn export_handler(
+    State(state): State<Arc<AppState>>,
+    JsonBody(query): JsonBody<DataSubjectQuery>,
+) -> Result<Json<ExportOutput>, ApiError> {
+    query.validate()?;
+    let action = DataRequestAction::Export;
+
+    let data = match state.repo.find_personal_data(&query.email).await {
+        Ok(data) => data,
+        Err(err) => return Err(failure(&state, action, &query.email, err.to_string()).await),
+    };
+    let subscriber = match state.email.find_subscriber(&query.email).await {
+        Ok(subscriber) => subscriber,
+        Err(err) => return Err(failure(&state, action, &query.email, err.to_string()).await),
+    };
+
+    record(&state, action, &query.email, Ok(None)).await;
+    Ok(Json(ExportOutput { data, subscriber }))
+}
+
+// Deletes everything stored about an email. The subscriber is forgotten by the email provider
+// first, so nothing is deleted from the database if that fails and the request can be retried.
+pub async fn erase_handler(
+    State(state): State<Arc<AppState>>,
+    JsonBody(query): JsonBody<DataSubjectQuery>,
+) -> Result<Json<EraseOutput>, ApiError> {
+    query.validate()?;
+    let action = DataRequestAction::Erase;
+
+    let subscriber = match state.email.find_subscriber(&query.email).await {
+        Ok(subscriber) => subscriber,
+        Err(err) => return Err(failure(&state, action, &query.email, err.to_string()).await),
+    };
+    if let Some(subscriber) = &subscriber {
+        if let Err(err) = state.email.forget_subscriber(&subscriber.id).await {
+            return Err(failure(&state, action, &query.email, err.to_string()).await);
+        }
+    }
+    let erased = match state.repo.erase_personal_data(&query.email).await {
+        Ok(erased) => erased,
+        Err(err) => return Err(failure(&state, action, &query.email, err.to_string()).await),
+    };
+
+    record(&state, action, &query.email, Ok(Some(erased.clone()))).await;
+    Ok(Json(EraseOutput {
+        success: true,
+        erased,
+        subscriber_forgotten: subscriber.is_some(),
+    }))
+}
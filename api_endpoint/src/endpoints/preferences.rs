@@ -0,0 +1,385 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    auth::WalletSession,
+    error::ApiError,
+    extract::{JsonBody, QueryParams},
+    models::AppState,
+    validation::{self, ValidationErrors},
+};
+use axum::{extract::State, Json};
+use sales_common::{
+    crypto::{self, PREFERENCES},
+    email::{EmailRequest, Subscriber},
+    repository::NewsletterStatus,
+    utils::to_hex,
+};
+use serde_derive::{Deserialize, Serialize};
+use starknet::core::types::FieldElement;
+
+// Like the unsubscribe token, it identifies the email through its keyed hash, but it expires
+// after preferences_validity seconds
+pub fn preferences_token(state: &AppState, email: &str) -> String {
+    let expires_at = chrono::Utc::now().timestamp() + state.conf.newsletter.preferences_validity;
+    crypto::preferences_token(&state.tokens, state.repo.cipher(), email, expires_at)
+}
+
+// The token of a preference center link, or the email bought with by the wallet of the session
+#[derive(Serialize, Deserialize)]
+pub struct PreferencesQuery {
+    token: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GroupPreference {
+    group: String,
+    subscribed: bool,
+}
+
+#[derive(Serialize)]
+pub struct PreferencesOutput {
+    email: String,
+    groups: Vec<GroupPreference>,
+    newsletter: Option<NewsletterStatus>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct PreferencesChanges {
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+impl PreferencesChanges {
+    fn validate(&self, groups: &[String]) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors::default();
+        for group in &self.add {
+            errors.check("add", validation::one_of(group, groups));
+        }
+        for group in &self.remove {
+            errors.check("remove", validation::one_of(group, groups));
+            if self.add.contains(group) {
+                errors.add("remove", format!("{} is also added", group));
+            }
+        }
+        errors.into_result()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ChangesOutput {
+    success: bool,
+    added: Vec<String>,
+    removed: Vec<String>,
+    // groups the email provider failed to change, the request can be retried
+    failed: Vec<String>,
+}
+
+// What a request to the email provider changes
+#[derive(Debug, PartialEq)]
+enum Change {
+    Add(Vec<String>),
+    Remove(String),
+}
+
+// Requests to the email provider applying the changes. Groups the subscriber already is in, or
+// isn't in, need no request.
+fn provider_requests(
+    email: &str,
+    subscriber: Option<&Subscriber>,
+    changes: &PreferencesChanges,
+) -> Vec<(EmailRequest, Change)> {
+    let Some(subscriber) = subscriber else {
+        if changes.add.is_empty() {
+            return Vec::new();
+        }
+        let request = EmailRequest::UpsertSubscriber {
+            email: email.to_string(),
+            fields: BTreeMap::new(),
+            groups: changes.add.clone(),
+        };
+        return vec![(request, Change::Add(changes.add.clone()))];
+    };
+    let adds = changes
+        .add
+        .iter()
+        .filter(|group| !subscriber.groups.contains(group))
+        .map(|group| {
+            let request = EmailRequest::AddToGroup {
+                subscriber_id: subscriber.id.clone(),
+                group: group.clone(),
+            };
+            (request, Change::Add(vec![group.clone()]))
+        });
+    let removes = changes
+        .remove
+        .iter()
+        .filter(|group| subscriber.groups.contains(group))
+        .map(|group| {
+            let request = EmailRequest::RemoveFromGroup {
+                subscriber_id: subscriber.id.clone(),
+                group: group.clone(),
+            };
+            (request, Change::Remove(group.clone()))
+        });
+    adds.chain(removes).collect()
+}
+
+// Email identified by the token
+async fn token_email(state: &AppState, token: &str) -> Result<(String, String), ApiError> {
+    let now = chrono::Utc::now().timestamp();
+    let Some(email_hash) = state.tokens.verify_until(PREFERENCES, token, now) else {
+        return Err(ApiError::BadRequest("invalid or expired token".to_string()));
+    };
+    match state.repo.find_email(&email_hash).await {
+        Ok(Some(email)) => Ok((email, email_hash)),
+        Ok(None) => Err(ApiError::NotFound("unknown email".to_string())),
+        Err(err) => Err(ApiError::internal(
+            &state.logger,
+            "Failed to find the email",
+            err,
+        )),
+    }
+}
+
+// Whether the address of a session is one of the wallets, which are indexed as emitted
+fn owns_wallet(wallets: &[String], address: &str) -> bool {
+    wallets.iter().any(|wallet| {
+        FieldElement::from_hex_be(wallet).is_ok_and(|wallet| to_hex(wallet) == address)
+    })
+}
+
+// Email identified by the token, or bought or renewed with by the wallet of the session
+async fn preferences_email(
+    state: &AppState,
+    query: &PreferencesQuery,
+    session: Option<&WalletSession>,
+) -> Result<(String, String), ApiError> {
+    if let Some(token) = &query.token {
+        return token_email(state, token).await;
+    }
+    let (Some(session), Some(email)) = (session, &query.email) else {
+        return Err(ApiError::Unauthorized(
+            "a token, or a wallet session along with an email, is required".to_string(),
+        ));
+    };
+    let email_hash = state.repo.cipher().blind_index(email);
+    let wallets = state
+        .repo
+        .find_email_wallets(&email_hash)
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to find email wallets", err))?;
+    if !owns_wallet(&wallets, &session.address) {
+        return Err(ApiError::Unauthorized(
+            "this wallet didn't buy or renew a domain with this email".to_string(),
+        ));
+    }
+    Ok((email.clone(), email_hash))
+}
+
+// The offered groups along with whether the email joined them, according to the email provider
+// or to the groups chosen in its transactions
+pub async fn get_handler(
+    State(state): State<Arc<AppState>>,
+    session: Option<WalletSession>,
+    QueryParams(query): QueryParams<PreferencesQuery>,
+) -> Result<Json<PreferencesOutput>, ApiError> {
+    let (email, email_hash) = preferences_email(&state, &query, session.as_ref()).await?;
+    let chosen = state
+        .repo
+        .find_email_groups(&email_hash)
+        .await
+        .map_err(|err| ApiError::internal(&state.logger, "Failed to find email groups", err))?;
+    let newsletter = state
+        .repo
+        .find_newsletter_by_hash(&email_hash)
+        .await
+        .map_err(|err| {
+            ApiError::internal(&state.logger, "Failed to find newsletter subscription", err)
+        })?;
+    let subscriber = state
+        .email
+        .find_subscriber(&email)
+        .await
+        .map_err(|err| ApiError::email_provider(&state.logger, err))?;
+
+    let groups = state
+        .conf
+        .mail_subscribe
+        .groups
+        .iter()
+        .map(|group| GroupPreference {
+            group: group.clone(),
+            subscribed: subscriber
+                .as_ref()
+                .is_some_and(|subscriber| subscriber.groups.contains(group))
+                || chosen.iter().any(|chosen| &chosen.group == group),
+        })
+        .collect();
+    Ok(Json(PreferencesOutput {
+        email,
+        groups,
+        newsletter: newsletter.map(|newsletter| newsletter.status),
+    }))
+}
+
+// Adds and removes groups with a single batch to the email provider. Removed groups are also
+// removed from the transactions of the email, so processing them doesn't add them back.
+pub async fn post_handler(
+    State(state): State<Arc<AppState>>,
+    session: Option<WalletSession>,
+    QueryParams(query): QueryParams<PreferencesQuery>,
+    JsonBody(mut changes): JsonBody<PreferencesChanges>,
+) -> Result<Json<ChangesOutput>, ApiError> {
+    let (email, email_hash) = preferences_email(&state, &query, session.as_ref()).await?;
+    changes.validate(&state.conf.mail_subscribe.groups)?;
+    changes.add.sort();
+    changes.add.dedup();
+    changes.remove.sort();
+    changes.remove.dedup();
+
+    let subscriber = state
+        .email
+        .find_subscriber(&email)
+        .await
+        .map_err(|err| ApiError::email_provider(&state.logger, err))?;
+    let (requests, planned): (Vec<EmailRequest>, Vec<Change>) =
+        provider_requests(&email, subscriber.as_ref(), &changes)
+            .into_iter()
+            .unzip();
+    let outcome = state
+        .email
+        .execute_batch(&requests)
+        .await
+        .map_err(|err| ApiError::email_provider(&state.logger, err))?;
+
+    // groups without a request were already as asked
+    let mut failed = Vec::new();
+    for (change, result) in planned.into_iter().zip(outcome) {
+        if let Err(err) = result {
+            state
+                .logger
+                .warning(format!("Failed to change email preferences: {}", err));
+            match change {
+                Change::Add(groups) => failed.extend(groups),
+                Change::Remove(group) => failed.push(group),
+            }
+        }
+    }
+    let succeeded = |groups: Vec<String>| -> Vec<String> {
+        groups
+            .into_iter()
+            .filter(|group| !failed.contains(group))
+            .collect()
+    };
+    let added = succeeded(changes.add);
+    let removed = succeeded(changes.remove);
+
+    if !removed.is_empty() {
+        state
+            .repo
+            .remove_email_groups(&email_hash, &removed)
+            .await
+            .map_err(|err| {
+                ApiError::internal(&state.logger, "Failed to remove email groups", err)
+            })?;
+    }
+
+    Ok(Json(ChangesOutput {
+        success: failed.is_empty(),
+        added,
+        removed,
+        failed,
+    }))
+}
+
+#[cfg(test)]
+mod preferences_tests {
+    use super::{owns_wallet, provider_requests, Change, PreferencesChanges};
+    use sales_common::email::{EmailRequest, Subscriber};
+    use std::collections::BTreeMap;
+
+    fn groups(groups: &[&str]) -> Vec<String> {
+        groups.iter().map(|group| group.to_string()).collect()
+    }
+
+    fn changes(add: &[&str], remove: &[&str]) -> PreferencesChanges {
+        PreferencesChanges {
+            add: groups(add),
+            remove: groups(remove),
+        }
+    }
+
+    #[test]
+    fn test_changes_are_limited_to_offered_groups() {
+        let offered = groups(&["1", "2"]);
+        assert!(changes(&["1"], &["2"]).validate(&offered).is_ok());
+        let errors = changes(&["3"], &["1"]).validate(&offered).unwrap_err();
+        assert_eq!(errors.get("add"), Some("must be one of 1, 2"));
+        let errors = changes(&["1"], &["1"]).validate(&offered).unwrap_err();
+        assert_eq!(errors.get("remove"), Some("1 is also added"));
+    }
+
+    #[test]
+    fn test_wallets_are_compared_normalized() {
+        // sessions are opened with the address as formatted by to_hex
+        let padded = "0x0000000000000000000000000000000000000000000000000000000000000456";
+        assert!(owns_wallet(&groups(&["0x123", "0x456"]), "0x0456"));
+        assert!(owns_wallet(&groups(&[padded]), "0x0456"));
+        assert!(!owns_wallet(&groups(&["0x123", "john"]), "0x0456"));
+        assert!(!owns_wallet(&[], "0x0456"));
+    }
+
+    #[test]
+    fn test_only_actual_changes_are_requested() {
+        let subscriber = Subscriber {
+            id: "42".to_string(),
+            email: "john@example.com".to_string(),
+            fields: BTreeMap::new(),
+            groups: groups(&["1", "2"]),
+        };
+        let requests = provider_requests(
+            "john@example.com",
+            Some(&subscriber),
+            &changes(&["1", "3"], &["2", "4"]),
+        );
+        assert_eq!(
+            requests,
+            vec![
+                (
+                    EmailRequest::AddToGroup {
+                        subscriber_id: "42".to_string(),
+                        group: "3".to_string(),
+                    },
+                    Change::Add(groups(&["3"]))
+                ),
+                (
+                    EmailRequest::RemoveFromGroup {
+                        subscriber_id: "42".to_string(),
+                        group: "2".to_string(),
+                    },
+                    Change::Remove("2".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_subscribers_are_created_with_their_groups() {
+        let requests = provider_requests("john@example.com", None, &changes(&["1"], &["2"]));
+        assert_eq!(
+            requests,
+            vec![(
+                EmailRequest::UpsertSubscriber {
+                    email: "john@example.com".to_string(),
+                    fields: BTreeMap::new(),
+                    groups: groups(&["1"]),
+                },
+                Change::Add(groups(&["1"]))
+            )]
+        );
+        assert!(provider_requests("john@example.com", None, &changes(&[], &["2"])).is_empty());
+    }
+}
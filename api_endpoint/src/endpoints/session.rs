@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use crate::{
+    auth::{AuthError, Challenge, Session},
+    error::ApiError,
+    extract::{JsonBody, QueryParams},
+    models::AppState,
+};
+use axum::{extract::State, Json};
+use serde_derive::Deserialize;
+use starknet::core::types::FieldElement;
+
+#[derive(Deserialize)]
+pub struct ChallengeQuery {
+    address: FieldElement,
+}
+
+#[derive(Deserialize)]
+pub struct SessionQuery {
+    address: FieldElement,
+    challenge: String,
+    signature: Vec<FieldElement>,
+}
+
+// Typed data for the wallet to sign, see the auth module
+pub async fn challenge_handler(
+    State(state): State<Arc<AppState>>,
+    QueryParams(query): QueryParams<ChallengeQuery>,
+) -> Json<Challenge> {
+    Json(
+        state
+            .auth
+            .challenge(query.address, chrono::Utc::now().timestamp()),
+    )
+}
+
+// Opens a wallet session once the account contract accepted the signature of the challenge
+pub async fn session_handler(
+    State(state): State<Arc<AppState>>,
+    JsonBody(query): JsonBody<SessionQuery>,
+) -> Result<Json<Session>, ApiError> {
+    let now = chrono::Utc::now().timestamp();
+    match state
+        .auth
+        .open_session(query.address, &query.challenge, &query.signature, now)
+        .await
+    {
+        Ok(session) => Ok(Json(session)),
+        Err(err @ (AuthError::InvalidChallenge | AuthError::InvalidSignature)) => {
+            Err(ApiError::Unauthorized(err.to_string()))
+        }
+        Err(err) => Err(ApiError::internal(
+            &state.logger,
+            "Failed to verify the signature",
+            err,
+        )),
+    }
+}
@@ -0,0 +1,203 @@
+use crate::validation::ValidationErrors;
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sales_common::{email::EmailError, logger::Logger};
+use serde_derive::Serialize;
+use std::fmt::Display;
+
+// Seconds to wait when the email provider rate limits us without telling for how long
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
+// Errors of the API, answered with a body like
+// {"error": {"code": "conflict", "message": "Email already exists"}}
+// Internal errors are logged where they happen and answered without their details.
+#[derive(Debug, PartialEq)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    NotFound(String),
+    Conflict(String),
+    UnsupportedMediaType(String),
+    Validation(ValidationErrors),
+    // a dependency, like the email provider, asked us to slow down
+    Unavailable { retry_after: u64 },
+    Internal,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetails<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetails<'a> {
+    code: &'static str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fields: Option<&'a ValidationErrors>,
+}
+
+impl ApiError {
+    // Logs an unexpected failure, `context` tells what we were doing
+    pub fn internal(logger: &Logger, context: &str, err: impl Display) -> Self {
+        logger.severe(format!("{}: {}", context, err));
+        ApiError::Internal
+    }
+
+    // Failure of a request to the email provider, its rate limits make us unavailable for a while
+    pub fn email_provider(logger: &Logger, err: EmailError) -> Self {
+        match err {
+            EmailError::RateLimited { retry_after } => {
+                logger.warning("Email provider rate limit reached");
+                ApiError::Unavailable {
+                    retry_after: retry_after.unwrap_or(DEFAULT_RETRY_AFTER),
+                }
+            }
+            err => ApiError::internal(logger, "Failed to send request to Mailerlite", err),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Unavailable { .. } => "unavailable",
+            ApiError::Internal => "internal_error",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Unauthorized(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::UnsupportedMediaType(message) => message,
+            ApiError::Validation(_) => "invalid request",
+            ApiError::Unavailable { .. } => "Too many requests, retry later",
+            ApiError::Internal => "Internal server error",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetails {
+                code: self.code(),
+                message: self.message(),
+                fields: match &self {
+                    ApiError::Validation(errors) => Some(errors),
+                    _ => None,
+                },
+            },
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let ApiError::Unavailable { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after.into());
+        }
+        response
+    }
+}
+
+impl From<ValidationErrors> for ApiError {
+    fn from(errors: ValidationErrors) -> Self {
+        ApiError::Validation(errors)
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            JsonRejection::MissingJsonContentType(_) => {
+                ApiError::UnsupportedMediaType(rejection.body_text())
+            }
+            _ => ApiError::BadRequest(rejection.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        ApiError::BadRequest(rejection.body_text())
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use super::ApiError;
+    use crate::validation::ValidationErrors;
+    use axum::{http::header, response::IntoResponse};
+    use reqwest::StatusCode;
+    use serde_json::{json, Value};
+
+    async fn body(error: ApiError) -> Value {
+        let response = error.into_response();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_errors_have_a_code_and_a_message() {
+        let error = ApiError::Conflict("Email already exists".to_string());
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            body(error).await,
+            json!({ "error": { "code": "conflict", "message": "Email already exists" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validation_errors_list_their_fields() {
+        let mut errors = ValidationErrors::default();
+        errors.add("email", "invalid email address");
+        let error = ApiError::from(errors);
+        assert_eq!(error.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body(error).await,
+            json!({ "error": {
+                "code": "validation_failed",
+                "message": "invalid request",
+                "fields": { "email": "invalid email address" }
+            } })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unavailability_tells_when_to_retry() {
+        let response = ApiError::Unavailable { retry_after: 30 }.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+    }
+
+    #[tokio::test]
+    async fn test_internal_errors_are_not_disclosed() {
+        assert_eq!(
+            body(ApiError::Internal).await,
+            json!({ "error": { "code": "internal_error", "message": "Internal server error" } })
+        );
+    }
+}
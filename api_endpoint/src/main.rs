@@ -1,16 +1,29 @@
 #[macro_use]
-mod utils;
+extern crate sales_common;
+mod admin;
+mod auth;
 mod config;
 mod endpoints;
-mod logger;
+mod error;
+mod extract;
 mod models;
+#[cfg(test)]
+mod test_utils;
+mod validation;
 use axum::{
     http::StatusCode,
+    middleware,
     routing::{get, post},
     Router,
 };
-use logger::Logger;
-use mongodb::{bson::doc, options::ClientOptions, Client};
+use mongodb::{options::ClientOptions, Client};
+use sales_common::{
+    config::{is_migrate_command, is_rotate_keys_command},
+    crypto::{Cipher, TokenSigner},
+    email,
+    logger::Logger,
+    repository::{migrations, Repository},
+};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
@@ -23,25 +36,101 @@ async fn main() {
     let client_options = ClientOptions::parse(&conf.database.connection_string)
         .await
         .unwrap();
+    let cipher = match Cipher::new(&conf.encryption) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            logger.severe(format!("invalid encryption config: {}", err));
+            return;
+        }
+    };
+    let tokens = match TokenSigner::new(&conf.newsletter.token_key) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            logger.severe(format!("invalid newsletter config: {}", err));
+            return;
+        }
+    };
+    let accounts = match auth::RpcAccountVerifier::new(&conf.auth.rpc_url) {
+        Ok(accounts) => accounts,
+        Err(err) => {
+            logger.severe(format!("invalid auth config: {}", err));
+            return;
+        }
+    };
+    let wallet_auth = match auth::WalletAuth::new(&conf.auth, Arc::new(accounts)) {
+        Ok(wallet_auth) => wallet_auth,
+        Err(err) => {
+            logger.severe(format!("invalid auth config: {}", err));
+            return;
+        }
+    };
     let shared_state = Arc::new(models::AppState {
         conf: conf.clone(),
         logger: logger.clone(),
-        db: Client::with_options(client_options)
-            .unwrap()
-            .database(&conf.database.name),
+        repo: Repository::new(
+            Client::with_options(client_options)
+                .unwrap()
+                .database(&conf.database.name),
+            cipher,
+        ),
+        email: email::new_provider(
+            &conf.email.provider,
+            conf.email.api_version,
+            &conf.email.base_url,
+            &conf.email.api_key,
+        ),
+        tokens,
+        auth: wallet_auth,
     });
-    if shared_state
-        .db
-        .run_command(doc! {"ping": 1}, None)
-        .await
-        .is_err()
-    {
+    if shared_state.repo.ping().await.is_err() {
         logger.severe("unable to connect to database");
         return;
     } else {
         logger.info("database: connected")
     }
 
+    if !migrations::apply(&shared_state.repo, &logger).await || is_migrate_command() {
+        return;
+    }
+    if is_rotate_keys_command() {
+        match shared_state.repo.reencrypt().await {
+            Ok(count) => logger.info(format!("encryption: re-encrypted {} documents", count)),
+            Err(err) => logger.severe(format!("unable to rotate keys: {}", err)),
+        }
+        return;
+    }
+
+    let admin = Router::new()
+        .route(
+            "/personal_data/export",
+            post(endpoints::personal_data::export_handler),
+        )
+        .route(
+            "/personal_data/erase",
+            post(endpoints::personal_data::erase_handler),
+        )
+        .route("/sales", get(endpoints::operations::sales_handler))
+        .route(
+            "/notifications",
+            get(endpoints::operations::notifications_handler),
+        )
+        .route(
+            "/notifications/resend",
+            post(endpoints::operations::resend_handler),
+        )
+        .route(
+            "/processed/purge",
+            post(endpoints::operations::purge_handler),
+        )
+        .route(
+            "/subscribers",
+            get(endpoints::operations::subscribers_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            admin::require_api_key,
+        ));
+
     let cors = CorsLayer::new().allow_headers(Any).allow_origin(Any);
     let app = Router::new()
         .route("/", get(root))
@@ -51,6 +140,27 @@ async fn main() {
             "/newsletter_subscribe",
             post(endpoints::newsletter_subscribe::handler),
         )
+        .route(
+            "/newsletter_confirm",
+            get(endpoints::newsletter_confirm::page_handler)
+                .post(endpoints::newsletter_confirm::handler),
+        )
+        .route(
+            "/newsletter_unsubscribe",
+            get(endpoints::newsletter_unsubscribe::page_handler)
+                .post(endpoints::newsletter_unsubscribe::handler),
+        )
+        .route(
+            "/auth/challenge",
+            get(endpoints::session::challenge_handler),
+        )
+        .route("/auth/session", post(endpoints::session::session_handler))
+        .route(
+            "/preferences",
+            get(endpoints::preferences::get_handler).post(endpoints::preferences::post_handler),
+        )
+        .nest("/admin", admin)
+        .fallback(not_found)
         .with_state(shared_state)
         .layer(cors);
 
@@ -68,3 +178,7 @@ async fn root() -> (StatusCode, String) {
         format!("server v{}", env!("CARGO_PKG_VERSION")),
     )
 }
+
+async fn not_found() -> error::ApiError {
+    error::ApiError::NotFound("unknown endpoint".to_string())
+}
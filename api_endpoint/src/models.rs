@@ -1,9 +1,15 @@
-use mongodb::Database;
+use sales_common::{
+    crypto::TokenSigner, email::EmailProvider, logger::Logger, repository::Repository,
+};
+use std::sync::Arc;
 
-use crate::{config::Config, logger::Logger};
+use crate::{auth::WalletAuth, config::Config};
 
 pub_struct!(;AppState {
     conf: Config,
     logger : Logger,
-    db: Database,
+    repo: Repository,
+    email: Arc<dyn EmailProvider>,
+    tokens: TokenSigner,
+    auth: WalletAuth,
 });
@@ -0,0 +1,109 @@
+use crate::{
+    auth::{AccountVerifier, AuthError, WalletAuth},
+    config::Config,
+    models::AppState,
+};
+use axum::async_trait;
+use mongodb::Database;
+use sales_common::{
+    crypto::{Cipher, TokenSigner},
+    email::InMemoryEmailProvider,
+    logger::Logger,
+    repository::Repository,
+};
+use starknet::core::types::FieldElement;
+use std::sync::Arc;
+
+pub use sales_common::repository::test_utils::test_db;
+
+pub fn config() -> Config {
+    toml::from_str(
+        r#"
+        [server]
+        port = 8080
+
+        [admin]
+        api_key_hashes = []
+
+        [auth]
+        token_key = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA="
+        rpc_url = "http://localhost"
+        chain_id = "SN_MAIN"
+        challenge_window = 300
+        session_duration = 3600
+
+        [newsletter]
+        token_key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+        confirmation_group_id = "confirmation_group"
+        confirmation_window = 86400
+        preferences_validity = 2592000
+
+        [metadata]
+        tax_states = ["none", "FR"]
+        min_salt_length = 1
+        verify_sales = false
+        verification_window = 3600
+
+        [mail_subscribe]
+        groups = ["1", "2"]
+        verify_transactions = false
+        verification_window = 3600
+
+        [database]
+        name = "test"
+        connection_string = "mongodb://localhost:27017"
+
+        [encryption]
+        current_key = "k1"
+        index_key = "aW5kZXhpbmRleGluZGV4aW5kZXhpbmRleGluZGV4MTI="
+        [encryption.keys]
+        k1 = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+
+        [email]
+        provider = "memory"
+        api_version = "connect"
+        base_url = "http://localhost"
+        api_key = "xxx"
+        ar_group_id = "ar_group"
+
+        [watchtower]
+        enabled = false
+        endpoint = "http://localhost"
+        app_id = "xxx"
+        token = "xxx"
+        [watchtower.types]
+        info = "info"
+        warning = "warning"
+        severe = "severe"
+        "#,
+    )
+    .unwrap()
+}
+
+// Tests hand the handlers a WalletSession, no signature is checked
+struct NoAccounts;
+
+#[async_trait]
+impl AccountVerifier for NoAccounts {
+    async fn is_valid_signature(
+        &self,
+        _address: FieldElement,
+        _hash: FieldElement,
+        _signature: &[FieldElement],
+    ) -> Result<bool, AuthError> {
+        Ok(false)
+    }
+}
+
+// State of the API on `db`, with `email` as the email provider
+pub fn state(db: &Database, email: Arc<InMemoryEmailProvider>) -> Arc<AppState> {
+    let conf = config();
+    Arc::new(AppState {
+        logger: Logger::new(&conf.watchtower),
+        repo: Repository::new(db.clone(), Cipher::new(&conf.encryption).unwrap()),
+        email,
+        tokens: TokenSigner::new(&conf.newsletter.token_key).unwrap(),
+        auth: WalletAuth::new(&conf.auth, Arc::new(NoAccounts)).unwrap(),
+        conf,
+    })
+}
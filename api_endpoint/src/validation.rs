@@ -0,0 +1,118 @@
+use crate::error::ApiError;
+use axum::response::{IntoResponse, Response};
+use email_address::EmailAddress;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+
+// Field level errors of a request, answered with a 422 listing them under `fields`, like
+// {"error": {"code": "validation_failed", ..., "fields": {"email": "invalid email address"}}}
+#[derive(Serialize, Default, Debug, PartialEq)]
+#[serde(transparent)]
+pub struct ValidationErrors {
+    errors: BTreeMap<&'static str, String>,
+}
+
+impl ValidationErrors {
+    pub fn add(&mut self, field: &'static str, message: impl Into<String>) {
+        self.errors.entry(field).or_insert(message.into());
+    }
+
+    pub fn check(&mut self, field: &'static str, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.add(field, message);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, field: &str) -> Option<&str> {
+        self.errors.get(field).map(|message| message.as_str())
+    }
+
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl IntoResponse for ValidationErrors {
+    fn into_response(self) -> Response {
+        ApiError::Validation(self).into_response()
+    }
+}
+
+pub fn email(email: &str) -> Result<(), String> {
+    if EmailAddress::is_valid(email) {
+        Ok(())
+    } else {
+        Err("invalid email address".to_string())
+    }
+}
+
+pub fn one_of(value: &str, allowed: &[String]) -> Result<(), String> {
+    if allowed.iter().any(|allowed| allowed == value) {
+        Ok(())
+    } else {
+        Err(format!("must be one of {}", allowed.join(", ")))
+    }
+}
+
+// Hexadecimal string, optionally 0x prefixed, of at least `min_length` digits
+pub fn hex(value: &str, min_length: usize) -> Result<(), String> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        Err("must be an hexadecimal string".to_string())
+    } else if digits.len() < min_length {
+        Err(format!(
+            "must have at least {} hexadecimal digits",
+            min_length
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod validation_tests {
+    use super::{email, hex, one_of, ValidationErrors};
+
+    #[test]
+    fn test_email() {
+        assert!(email("john@example.com").is_ok());
+        assert!(email("john@").is_err());
+        assert!(email("").is_err());
+    }
+
+    #[test]
+    fn test_one_of() {
+        let allowed = vec!["FR".to_string(), "US-CA".to_string()];
+        assert!(one_of("US-CA", &allowed).is_ok());
+        assert_eq!(
+            one_of("fr", &allowed),
+            Err("must be one of FR, US-CA".to_string())
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert!(hex("0x0123456789abcdef", 16).is_ok());
+        assert!(hex("0123456789ABCDEF", 16).is_ok());
+        assert!(hex("0x1234", 16).is_err());
+        assert!(hex("0x", 0).is_err());
+        assert!(hex("0xnot_hex_at_all!", 4).is_err());
+    }
+
+    #[test]
+    fn test_first_error_of_a_field_is_kept() {
+        let mut errors = ValidationErrors::default();
+        errors.check("email", Ok(()));
+        errors.add("salt", "too short");
+        errors.add("salt", "not hex");
+        assert_eq!(errors.get("email"), None);
+        assert_eq!(errors.get("salt"), Some("too short"));
+        assert!(errors.into_result().is_err());
+        assert!(ValidationErrors::default().into_result().is_ok());
+    }
+}
@@ -1,60 +1,88 @@
+use sales_common::config::{Database, Encryption, Watchtower};
+use sales_common::email::{ApiVersion, EmailProviderKind};
 use serde::{self, Deserialize};
-use std::env;
-use std::fs;
 
 pub_struct!(Clone, Deserialize; General {
     check_delay: u64,
+    renewal_sync: bool,
 });
 
 pub_struct!(Clone, Deserialize; Email {
+    provider: EmailProviderKind,
+    api_version: ApiVersion,
     base_url : String,
     api_key: String,
     ar_group_id : String,
     batch_size : usize,
 });
 
-pub_struct!(Clone, Deserialize; Database {
-    name: String,
-    connection_string: String,
+// Retry policy of the notification outbox, delays are in seconds
+pub_struct!(Clone, Deserialize; Outbox {
+    max_attempts: i32,
+    base_delay: u64,
+    max_delay: u64,
 });
 
-pub_struct!(Clone, Deserialize; WatchtowerTypes {
-    info: String,
-    warning: String,
-    severe: String,
-});
-
-pub_struct!(Clone, Deserialize; Watchtower {
-    enabled : bool,
-    endpoint: String,
-    app_id: String,
-    token: String,
-    types: WatchtowerTypes,
+// Group of the subscribers who didn't confirm their newsletter subscription yet, and the key
+// and seconds of validity of the preference center tokens sent with the notifications
+pub_struct!(Clone, Deserialize; Newsletter {
+    confirmation_group_id: String,
+    token_key: String,
+    preferences_validity: i64,
 });
 
 pub_struct!(Clone, Deserialize;  Config {
     general : General,
     email : Email,
+    outbox : Outbox,
+    newsletter: Newsletter,
     database: Database,
+    encryption: Encryption,
     watchtower: Watchtower,
 });
 
+impl Config {
+    fn validate(&self) -> Result<(), String> {
+        if self.email.batch_size == 0 {
+            return Err("email.batch_size must be at least 1".to_string());
+        }
+        if self.newsletter.preferences_validity <= 0 {
+            return Err("newsletter.preferences_validity must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
 pub fn load() -> Config {
-    let args: Vec<String> = env::args().collect();
-    let config_path = if args.len() <= 1 {
-        "config.toml"
-    } else {
-        args.get(1).unwrap()
-    };
-    let file_contents = fs::read_to_string(config_path);
-    if file_contents.is_err() {
-        panic!("error: unable to read file with path \"{}\"", config_path);
+    let conf: Config = sales_common::config::load();
+    if let Err(err) = conf.validate() {
+        panic!("error: invalid config. {}", err);
     }
+    conf
+}
 
-    match toml::from_str(file_contents.unwrap().as_str()) {
-        Ok(loaded) => loaded,
-        Err(err) => {
-            panic!("error: unable to deserialize config. {}", err);
-        }
+#[cfg(test)]
+mod config_tests {
+    use crate::processing::test_utils::config;
+
+    #[test]
+    fn test_batch_size_must_be_positive() {
+        let mut conf = config();
+        assert!(conf.validate().is_ok());
+        conf.email.batch_size = 0;
+        assert_eq!(
+            conf.validate(),
+            Err("email.batch_size must be at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_preferences_validity_must_be_positive() {
+        let mut conf = config();
+        conf.newsletter.preferences_validity = 0;
+        assert_eq!(
+            conf.validate(),
+            Err("newsletter.preferences_validity must be positive".to_string())
+        );
     }
 }
@@ -1,35 +1,83 @@
 #[macro_use]
-mod utils;
+extern crate sales_common;
 mod config;
-mod logger;
 mod processing;
-use logger::Logger;
-use mongodb::{bson::doc, options::ClientOptions, Client};
+use mongodb::{options::ClientOptions, Client};
+use sales_common::{
+    config::{is_migrate_command, is_rotate_keys_command},
+    crypto::{Cipher, TokenSigner},
+    email,
+    logger::Logger,
+    repository::{migrations, Repository},
+};
 use tokio::time::{sleep, Duration};
 
 #[tokio::main]
 async fn main() {
     let conf = config::load();
     let logger = Logger::new(&conf.watchtower);
-    logger.info(format!("starting v{} of sale_actions", env!("CARGO_PKG_VERSION")));
-    let db = Client::with_options(
-        ClientOptions::parse(&conf.database.connection_string)
-            .await
-            .unwrap(),
-    )
-    .unwrap()
-    .database(&conf.database.name);
+    logger.info(format!(
+        "starting v{} of sale_actions",
+        env!("CARGO_PKG_VERSION")
+    ));
+    let cipher = match Cipher::new(&conf.encryption) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            logger.severe(format!("invalid encryption config: {}", err));
+            return;
+        }
+    };
+    let tokens = match TokenSigner::new(&conf.newsletter.token_key) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            logger.severe(format!("invalid newsletter config: {}", err));
+            return;
+        }
+    };
+    let repo = Repository::new(
+        Client::with_options(
+            ClientOptions::parse(&conf.database.connection_string)
+                .await
+                .unwrap(),
+        )
+        .unwrap()
+        .database(&conf.database.name),
+        cipher,
+    );
 
-    if db.run_command(doc! {"ping": 1}, None).await.is_err() {
+    if repo.ping().await.is_err() {
         logger.severe("unable to connect to database");
         return;
     } else {
         logger.info("database: connected")
     }
 
+    if !migrations::apply(&repo, &logger).await || is_migrate_command() {
+        return;
+    }
+    if is_rotate_keys_command() {
+        match repo.reencrypt().await {
+            Ok(count) => logger.info(format!("encryption: re-encrypted {} documents", count)),
+            Err(err) => logger.severe(format!("unable to rotate keys: {}", err)),
+        }
+        return;
+    }
+
+    let provider = email::new_provider(
+        &conf.email.provider,
+        conf.email.api_version,
+        &conf.email.base_url,
+        &conf.email.api_key,
+    );
+
     loop {
-        processing::purchases::process_data(&conf, &db, &logger).await;
-        //processing::renewal::process_data(&conf, &db, &logger).await;
+        processing::verification::process_data(&repo, &logger).await;
+        processing::newsletter::purge_unconfirmed(&conf, &repo, &logger, provider.as_ref()).await;
+        processing::purchases::process_data(&conf, &repo, &logger, &tokens).await;
+        if conf.general.renewal_sync {
+            processing::renewal::process_data(&conf, &repo, &logger, &tokens).await;
+        }
+        processing::outbox::deliver(&conf, &repo, &logger, provider.as_ref()).await;
         sleep(Duration::from_secs(conf.general.check_delay)).await; // Sleep for 60 seconds before repeating
     }
 }
@@ -1,12 +1,45 @@
-use serde_derive::{Deserialize, Serialize};
+use crate::config::Config;
+use sales_common::{
+    crypto::{self, TokenSigner},
+    logger::Logger,
+    repository::{Ledger, Repository},
+};
 
+pub mod newsletter;
+pub mod outbox;
 pub mod purchases;
 pub mod renewal;
+#[cfg(test)]
+pub mod test_utils;
+pub mod verification;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MetadataDoc {
-    pub meta_hash: String,
-    pub email: String,
-    pub tax_state: String,
-    pub salt: String,
+// Token of the preference center link of a buyer, valid for preferences_validity seconds
+pub fn preferences_token(
+    conf: &Config,
+    repo: &Repository,
+    tokens: &TokenSigner,
+    email: &str,
+) -> String {
+    let expires_at = chrono::Utc::now().timestamp() + conf.newsletter.preferences_validity;
+    crypto::preferences_token(tokens, repo.cipher(), email, expires_at)
+}
+
+// Blacklist the processed (tx_hash, domain) keys, a replayed key is a no-op
+pub async fn mark_processed(
+    repo: &Repository,
+    logger: &Logger,
+    ledger: Ledger,
+    keys: Vec<(String, String)>,
+) {
+    for (tx_hash, domain) in keys {
+        if let Err(e) = repo.mark_processed(ledger, &tx_hash, &domain).await {
+            logger.severe(format!(
+                "Error marking {} of {} as processed in '{}' collection: {}",
+                domain,
+                tx_hash,
+                ledger.collection_name(),
+                e
+            ));
+        }
+    }
 }
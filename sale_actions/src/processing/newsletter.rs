@@ -0,0 +1,109 @@
+use crate::config::Config;
+use sales_common::{email::EmailProvider, logger::Logger, repository::Repository};
+
+// Purges the newsletter subscriptions that were not confirmed in time, after removing their
+// subscriber from the confirmation group. A subscriber the provider failed to update is kept to
+// be retried on the next check.
+pub async fn purge_unconfirmed(
+    conf: &Config,
+    repo: &Repository,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+) {
+    let expired = match repo.expired_newsletters().await {
+        Ok(expired) => expired,
+        Err(e) => {
+            logger.severe(format!(
+                "Error while listing unconfirmed subscriptions: {}",
+                e
+            ));
+            return;
+        }
+    };
+
+    let group = &conf.newsletter.confirmation_group_id;
+    let mut purged = 0;
+    for newsletter in expired {
+        let removed = match provider.find_subscriber(&newsletter.email).await {
+            Ok(Some(subscriber)) => provider.remove_from_group(&subscriber.id, group).await,
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = removed {
+            logger.severe(format!(
+                "Error removing unconfirmed subscriber from the confirmation group: {}",
+                e
+            ));
+            continue;
+        }
+        match repo.purge_newsletter(&newsletter.email_hash).await {
+            Ok(true) => purged += 1,
+            Ok(false) => (),
+            Err(e) => logger.severe(format!("Error purging unconfirmed subscription: {}", e)),
+        }
+    }
+    if purged > 0 {
+        logger.info(format!("purged {} unconfirmed subscriptions", purged));
+    }
+}
+
+#[cfg(test)]
+mod newsletter_tests {
+    use super::purge_unconfirmed;
+    use crate::processing::test_utils::{config, logger, setup};
+    use mongodb::bson::DateTime;
+    use sales_common::{
+        email::{EmailProvider, InMemoryEmailProvider},
+        repository::{NewsletterDoc, NewsletterStatus},
+    };
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_expired_subscriptions_are_purged() {
+        let Some((db, repo)) = setup("purge_unconfirmed").await else {
+            return;
+        };
+        let (conf, logger) = (config(), logger());
+        let provider = InMemoryEmailProvider::default();
+        let pending = |email: &str, confirm_until: i64| NewsletterDoc {
+            email: email.to_string(),
+            email_hash: String::new(),
+            address: None,
+            source: "newsletter_subscription".to_string(),
+            status: NewsletterStatus::Pending,
+            confirm_until: Some(DateTime::from_millis(confirm_until)),
+        };
+        let in_an_hour = DateTime::now().timestamp_millis() + 3_600_000;
+        for newsletter in [
+            pending("john@example.com", 0),
+            pending("jane@example.com", in_an_hour),
+        ] {
+            repo.insert_newsletter(&newsletter).await.unwrap();
+            provider
+                .upsert_subscriber(
+                    &newsletter.email,
+                    &BTreeMap::new(),
+                    &["confirmation_group".to_string()],
+                )
+                .await
+                .unwrap();
+        }
+
+        purge_unconfirmed(&conf, &repo, &logger, &provider).await;
+
+        assert_eq!(
+            repo.find_newsletter("john@example.com").await.unwrap(),
+            None
+        );
+        assert!(repo
+            .find_newsletter("jane@example.com")
+            .await
+            .unwrap()
+            .is_some());
+        let subscribers = provider.subscribers();
+        assert_eq!(subscribers[0].email, "jane@example.com");
+        assert_eq!(subscribers[0].groups, vec!["confirmation_group"]);
+        assert!(subscribers[1].groups.is_empty());
+        db.drop(None).await.unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use crate::config::Config;
+use mongodb::bson::DateTime;
+use sales_common::{
+    email::{EmailProvider, EmailRequest},
+    logger::Logger,
+    repository::{NotificationDoc, NotificationRequest, NotificationState, Repository},
+};
+use std::time::Duration;
+
+// Notifications are added to the outbox with `Repository::enqueue_notification`, then delivered
+// by `deliver` which retries failures with an exponential backoff until `max_attempts` is reached
+// and the notification is considered dead.
+
+// Delay before the next attempt once `attempts` attempts failed
+fn backoff(conf: &Config, attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 31) as u32;
+    let delay = conf
+        .outbox
+        .base_delay
+        .saturating_mul(2u64.saturating_pow(exponent));
+    Duration::from_secs(delay.min(conf.outbox.max_delay))
+}
+
+async fn record_result(
+    conf: &Config,
+    repo: &Repository,
+    notification: &NotificationDoc,
+    result: Result<(), String>,
+) -> mongodb::error::Result<()> {
+    let Err(error) = result else {
+        return repo.mark_notification_sent(notification).await;
+    };
+    let attempts = notification.attempts + 1;
+    let state = if attempts >= conf.outbox.max_attempts {
+        NotificationState::Dead
+    } else {
+        NotificationState::Failed
+    };
+    let next_attempt_at = DateTime::from_millis(
+        DateTime::now().timestamp_millis() + backoff(conf, attempts).as_millis() as i64,
+    );
+    repo.mark_notification_failed(notification, state, &error, next_attempt_at)
+        .await
+}
+
+// Request sent to the provider for a notification, None when there is nothing to send. The
+// subscriber removed from a group is looked up once the notifications enqueued before it for the
+// same email were sent, an enable still being retried would otherwise be undone by it.
+async fn resolve(
+    repo: &Repository,
+    provider: &dyn EmailProvider,
+    notification: &NotificationDoc,
+) -> Result<Option<EmailRequest>, String> {
+    match repo
+        .decrypt_request(notification)
+        .map_err(|e| e.to_string())?
+    {
+        NotificationRequest::Email(request) => Ok(Some(request)),
+        NotificationRequest::RemoveEmailFromGroup { email, group } => {
+            if repo
+                .has_earlier_notification(notification)
+                .await
+                .map_err(|e| e.to_string())?
+            {
+                return Err("waiting for an earlier notification of this email".to_string());
+            }
+            let subscriber = provider
+                .find_subscriber(&email)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(subscriber.map(|subscriber| EmailRequest::RemoveFromGroup {
+                subscriber_id: subscriber.id,
+                group,
+            }))
+        }
+    }
+}
+
+// Sends the notifications in one batch and returns their results. A request that can't be
+// decrypted or resolved fails without being sent.
+async fn send(
+    repo: &Repository,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+    notifications: &[NotificationDoc],
+) -> Vec<Result<(), String>> {
+    let mut resolved = Vec::with_capacity(notifications.len());
+    for notification in notifications {
+        resolved.push(resolve(repo, provider, notification).await);
+    }
+    let requests: Vec<EmailRequest> = resolved
+        .iter()
+        .filter_map(|request| request.as_ref().ok().cloned().flatten())
+        .collect();
+    let outcome: Vec<Result<(), String>> = if requests.is_empty() {
+        Vec::new()
+    } else {
+        match provider.execute_batch(&requests).await {
+            Ok(outcome) => outcome
+                .into_iter()
+                .map(|result| result.map_err(|e| e.to_string()))
+                .collect(),
+            Err(e) => {
+                logger.severe(format!("Failed to process batch request: {}", e));
+                requests.iter().map(|_| Err(e.to_string())).collect()
+            }
+        }
+    };
+    let mut outcome = outcome.into_iter();
+    resolved
+        .into_iter()
+        .map(|request| match request {
+            Ok(Some(_)) => outcome
+                .next()
+                .unwrap_or_else(|| Err("missing result in batch response".to_string())),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        })
+        .collect()
+}
+
+// Sends the due notifications in batches of `batch_size`. Stops when a result can't be recorded,
+// the same notifications would be fetched again otherwise.
+pub async fn deliver(
+    conf: &Config,
+    repo: &Repository,
+    logger: &Logger,
+    provider: &dyn EmailProvider,
+) {
+    let batch_size = conf.email.batch_size;
+    loop {
+        let notifications = match repo.due_notifications(batch_size as i64).await {
+            Ok(notifications) => notifications,
+            Err(e) => {
+                logger.severe(format!("Error reading the outbox: {}", e));
+                return;
+            }
+        };
+        if notifications.is_empty() {
+            return;
+        }
+
+        let results = send(repo, logger, provider, &notifications).await;
+
+        let mut recorded = true;
+        for (notification, result) in notifications.iter().zip(results) {
+            if let Err(error) = &result {
+                logger.warning(format!(
+                    "Failed to notify {} of {} (attempt {}): {}",
+                    notification.domain,
+                    notification.tx_hash,
+                    notification.attempts + 1,
+                    error
+                ));
+            }
+            if let Err(e) = record_result(conf, repo, notification, result).await {
+                logger.severe(format!(
+                    "Error updating outbox entry of {} in {}: {}",
+                    notification.domain, notification.tx_hash, e
+                ));
+                recorded = false;
+            }
+        }
+
+        if !recorded || notifications.len() < batch_size {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod outbox_tests {
+    use super::{backoff, deliver};
+    use crate::processing::test_utils::{config, logger, setup};
+    use mongodb::bson::DateTime;
+    use sales_common::{
+        email::{EmailRequest, InMemoryEmailProvider},
+        repository::{NotificationKind, NotificationRequest, NotificationState},
+    };
+    use std::{collections::BTreeMap, time::Duration};
+
+    #[test]
+    fn test_backoff_doubles_and_is_capped() {
+        let conf = config();
+        assert_eq!(backoff(&conf, 1), Duration::from_secs(60));
+        assert_eq!(backoff(&conf, 2), Duration::from_secs(120));
+        assert_eq!(backoff(&conf, 4), Duration::from_secs(480));
+        assert_eq!(backoff(&conf, 7), Duration::from_secs(3600));
+        assert_eq!(backoff(&conf, 100), Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn test_dead_notifications_are_delivered_once_resent() {
+        let Some((db, repo)) = setup("resend_dead").await else {
+            return;
+        };
+        let (conf, logger) = (config(), logger());
+        let kind = NotificationKind::Purchase;
+        let request = EmailRequest::UpsertSubscriber {
+            email: "john@example.com".to_string(),
+            fields: BTreeMap::new(),
+            groups: vec![],
+        };
+        let notification = NotificationRequest::Email(request.clone());
+        repo.enqueue_notification(kind, "0x1", "john.stark", &notification)
+            .await
+            .unwrap();
+        let due = repo.due_notifications(10).await.unwrap();
+        repo.mark_notification_failed(&due[0], NotificationState::Dead, "down", DateTime::now())
+            .await
+            .unwrap();
+
+        let provider = InMemoryEmailProvider::default();
+        deliver(&conf, &repo, &logger, &provider).await;
+        assert!(provider.executed_batches().is_empty());
+
+        assert!(repo
+            .resend_notification(kind, "0x1", "john.stark")
+            .await
+            .unwrap());
+        deliver(&conf, &repo, &logger, &provider).await;
+        assert_eq!(provider.executed_batches(), vec![vec![request]]);
+        let found = repo.find_notifications("0x1", "john.stark").await.unwrap();
+        assert_eq!(
+            (found[0].state, found[0].attempts, found[0].request.clone()),
+            (NotificationState::Sent, 1, None)
+        );
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_group_removals_wait_for_earlier_notifications_of_the_email() {
+        let Some((db, repo)) = setup("ordered_removals").await else {
+            return;
+        };
+        let (conf, logger) = (config(), logger());
+        let kind = NotificationKind::Renewal;
+        let enable = EmailRequest::UpsertSubscriber {
+            email: "john@example.com".to_string(),
+            fields: BTreeMap::new(),
+            groups: vec!["ar_group".to_string()],
+        };
+        let disable = |email: &str| NotificationRequest::RemoveEmailFromGroup {
+            email: email.to_string(),
+            group: "ar_group".to_string(),
+        };
+        let notification = NotificationRequest::Email(enable.clone());
+        repo.enqueue_notification(kind, "0x1", "john.stark", &notification)
+            .await
+            .unwrap();
+        repo.enqueue_notification(kind, "0x2", "john.stark", &disable("john@example.com"))
+            .await
+            .unwrap();
+        repo.enqueue_notification(kind, "0x3", "jane.stark", &disable("jane@example.com"))
+            .await
+            .unwrap();
+        // the enable failed and is retried later
+        let found = repo.find_notifications("0x1", "john.stark").await.unwrap();
+        let in_an_hour = DateTime::from_millis(DateTime::now().timestamp_millis() + 3_600_000);
+        repo.mark_notification_failed(&found[0], NotificationState::Failed, "down", in_an_hour)
+            .await
+            .unwrap();
+
+        let provider = InMemoryEmailProvider::default();
+        deliver(&conf, &repo, &logger, &provider).await;
+        // jane is not a subscriber, there is nothing to remove
+        assert!(provider.executed_batches().is_empty());
+        let found = repo.find_notifications("0x3", "jane.stark").await.unwrap();
+        assert_eq!(found[0].state, NotificationState::Sent);
+        let found = repo.find_notifications("0x2", "john.stark").await.unwrap();
+        assert_eq!(found[0].state, NotificationState::Failed);
+
+        for tx_hash in ["0x1", "0x2"] {
+            assert!(repo
+                .resend_notification(kind, tx_hash, "john.stark")
+                .await
+                .unwrap());
+        }
+        deliver(&conf, &repo, &logger, &provider).await;
+        assert_eq!(provider.executed_batches(), vec![vec![enable.clone()]]);
+        assert!(repo
+            .resend_notification(kind, "0x2", "john.stark")
+            .await
+            .unwrap());
+        deliver(&conf, &repo, &logger, &provider).await;
+        let removal = EmailRequest::RemoveFromGroup {
+            subscriber_id: "john@example.com".to_string(),
+            group: "ar_group".to_string(),
+        };
+        assert_eq!(
+            provider.executed_batches(),
+            vec![vec![enable], vec![removal]]
+        );
+        assert!(provider.subscribers()[0].groups.is_empty());
+        db.drop(None).await.unwrap();
+    }
+}
@@ -1,259 +1,182 @@
-use super::MetadataDoc;
-use crate::{config::Config, logger::Logger};
-use chrono::NaiveDateTime;
+use super::{mark_processed, preferences_token};
+use crate::config::Config;
+use chrono::DateTime;
 use futures::stream::StreamExt;
-use mongodb::{
-    bson::{doc, Document},
-    Collection, Database,
+use sales_common::{
+    crypto::TokenSigner,
+    email::EmailRequest,
+    logger::Logger,
+    repository::{Ledger, NotificationKind, NotificationRequest, Repository, SaleDoc},
 };
-use reqwest::{header, Client};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SaleDoc {
-    pub tx_hash: String,
-    pub domain: String,
-    pub price: f64,
-    pub payer: String,
-    pub timestamp: i64,
-    pub expiry: i64,
-    pub metadata: Vec<MetadataDoc>,
-    pub same_tx_groups: Vec<String>, // The new field
-}
+use std::collections::BTreeMap;
 
 // Adjusted process_sale to create a request object instead of directly sending
-fn create_sale_request(sale: &SaleDoc, base_url: &str) -> Value {
-    let groups_params: Vec<String> = sale
-        .same_tx_groups
-        .iter()
-        .map(|group| format!("groups[]={}", group))
-        .collect();
-
-    let url = format!(
-        "{base_url}/subscribers?email={email}&fields[name]={domain}&fields[expiry]={expiry}&{groups}",
-        base_url = base_url,
-        email = urlencoding::encode(&sale.metadata[0].email),
-        domain = urlencoding::encode(&sale.domain),
-        expiry = match NaiveDateTime::from_timestamp_opt(sale.expiry, 0) {
-            Some(time) => urlencoding::encode(&time.format("%Y-%m-%d %H:%M:%S").to_string()).to_string(),
-            _ => "none".to_string(),
-        },
-        groups = groups_params.join("&")
-    );
+fn create_sale_request(sale: &SaleDoc, preferences_token: String) -> EmailRequest {
+    let expiry = match DateTime::from_timestamp(sale.expiry, 0) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        _ => "none".to_string(),
+    };
 
-    json!({
-        "method": "POST",
-        "path": &url,
-    })
+    EmailRequest::UpsertSubscriber {
+        email: sale.metadata[0].email.clone(),
+        fields: BTreeMap::from([
+            ("name".to_string(), sale.domain.clone()),
+            ("expiry".to_string(), expiry),
+            ("preferences_token".to_string(), preferences_token),
+        ]),
+        groups: sale.same_tx_groups.clone(),
+    }
 }
 
-// process batch requests
-async fn process_batch(conf: &Config, logger: &Logger, sales: &[SaleDoc]) {
-    let requests: Vec<Value> = sales
-        .iter()
-        .map(|sale| create_sale_request(sale, &conf.email.base_url))
-        .collect();
-
-    let batch_request = json!({
-        "requests": requests
-    });
-
-    let client = Client::new();
-    match client
-        .post("https://api.mailerlite.com/api/v2/batch")
-        .header("X-MailerLite-ApiKey", &conf.email.api_key)
-        .header(header::CONTENT_TYPE, "application/json")
-        .json(&batch_request)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            if !res.status().is_success() {
-                logger.severe(format!(
-                    "Received non-success status from batch request: {}. Response body: {}",
-                    res.status(),
-                    res.text()
-                        .await
-                        .unwrap_or_else(|_| "Failed to retrieve response body".to_string())
-                ));
-            }
-        }
+// collect sales and add their notification to the outbox
+pub async fn process_data(conf: &Config, repo: &Repository, logger: &Logger, tokens: &TokenSigner) {
+    let mut sales = match repo.unprocessed_sales().await {
+        Ok(sales) => sales,
         Err(e) => {
-            logger.severe(format!("Failed to send batch request: {}", e));
+            logger.severe(format!("Error while aggregating purchases: {}", e));
+            return;
         }
-    }
-}
-
-// collect sales and process in batch
-pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
-    let pipeline: Vec<Document> = vec![
-        doc! {
-            "$match": doc! {
-                "meta_hash": doc! {
-                    "$ne": ""
-                }
-            }
-        },
-        doc! {
-            "$lookup": doc! {
-                "from": "metadata",
-                "let": doc! {
-                    "meta_hash": "$meta_hash"
-                },
-                "pipeline": [
-                    doc! {
-                        "$match": doc! {
-                            "$expr": doc! {
-                                "$eq": [
-                                    "$meta_hash",
-                                    "$$meta_hash"
-                                ]
-                            }
-                        }
-                    },
-                    doc! {
-                        "$project": doc! {
-                            "_id": 0,
-                            "meta_hash": 1
-                        }
-                    }
-                ],
-                "as": "metadata"
-            }
-        },
-        doc! {
-            "$match": doc! {
-                "metadata": doc! {
-                    "$ne": []
-                }
-            }
-        },
-        doc! {
-            "$lookup": doc! {
-                "from": "processed",
-                "let": doc! {
-                    "meta_hash": "$meta_hash"
-                },
-                "pipeline": [
-                    doc! {
-                        "$match": doc! {
-                            "$expr": doc! {
-                                "$eq": [
-                                    "$meta_hash",
-                                    "$$meta_hash"
-                                ]
-                            }
-                        }
-                    },
-                    doc! {
-                        "$project": doc! {
-                            "_id": 0,
-                            "meta_hash": 1
-                        }
-                    }
-                ],
-                "as": "processed_doc"
-            }
-        },
-        doc! {
-            "$match": doc! {
-                "processed_doc": doc! {
-                    "$eq": []
-                }
-            }
-        },
-        doc! {
-            "$lookup": doc! {
-                "from": "email_groups",
-                "let": doc! {
-                    "tx_hash": "$tx_hash"
-                },
-                "pipeline": [
-                    doc! {
-                        "$match": doc! {
-                            "$expr": doc! {
-                                "$eq": [
-                                    "$tx_hash",
-                                    "$$tx_hash"
-                                ]
-                            }
-                        }
-                    },
-                    doc! {
-                        "$project": doc! {
-                            "_id": 0,
-                            "group": 1
-                        }
-                    }
-                ],
-                "as": "same_tx_groups"
-            }
-        },
-        doc! {
-            "$project": doc! {
-                "meta_hash": 1,
-                "tx_hash": 1,
-                "same_tx_groups": doc! {
-                    "$map": doc! {
-                        "input": "$same_tx_groups",
-                        "as": "item",
-                        "in": "$$item.group"
-                    }
-                }
-            }
-        },
-    ];
-    let sales_collection: Collection<Document> = db.collection("sales");
-    let mut cursor = sales_collection.aggregate(pipeline, None).await.unwrap();
-    let mut batch = Vec::new();
+    };
     let mut processed = Vec::new();
 
-    let batch_size = conf.email.batch_size;
-    while let Some(result) = cursor.next().await {
+    while let Some(result) = sales.next().await {
         match result {
-            Ok(document) => match mongodb::bson::from_document::<SaleDoc>(document) {
-                Err(e) => {
-                    logger.severe(format!("Error parsing doc in purchase: {}", e));
-                }
-                Ok(sales_doc) => {
-                    processed.push(sales_doc.tx_hash.clone());
-                    batch.push(sales_doc);
-                    if batch.len() >= batch_size {
-                        process_batch(&conf, &logger, &batch).await;
-                        batch.clear();
+            Ok(mut sales_doc) => {
+                match repo.decrypt_metadata(&sales_doc.metadata[0]) {
+                    Ok(metadata) => sales_doc.metadata[0] = metadata,
+                    Err(e) => {
+                        // Not marked as processed so it is retried once the key is configured
+                        logger.severe(format!(
+                            "Error decrypting metadata of sale {}: {}",
+                            sales_doc.tx_hash, e
+                        ));
+                        continue;
                     }
                 }
-            },
+                let token = preferences_token(conf, repo, tokens, &sales_doc.metadata[0].email);
+                let request = NotificationRequest::Email(create_sale_request(&sales_doc, token));
+                let (tx_hash, domain) = (&sales_doc.tx_hash, &sales_doc.domain);
+                let kind = NotificationKind::Purchase;
+                match repo
+                    .enqueue_notification(kind, tx_hash, domain, &request)
+                    .await
+                {
+                    Ok(()) => processed.push((tx_hash.clone(), domain.clone())),
+                    Err(e) => logger.severe(format!(
+                        "Error adding sale {} to the outbox: {}",
+                        sales_doc.tx_hash, e
+                    )),
+                }
+            }
             Err(e) => {
-                logger.severe(format!("Error while processing: {}", e));
+                logger.severe(format!("Error while processing purchases: {}", e));
             }
         }
     }
 
-    // Process any remaining sales not reaching batch size
-    if !batch.is_empty() {
-        process_batch(&conf, &logger, &batch).await;
-    }
+    // Blacklist the processed sales, their delivery is now tracked by the outbox
+    mark_processed(repo, logger, Ledger::Purchases, processed).await;
+}
 
-    // Blacklist the processed documents
-    let processed_collection: Collection<Document> = db.collection("processed");
-    match processed_collection
-        .insert_many(
-            processed
-                .iter()
-                .map(|tx_hash| doc! { "meta_hash": tx_hash })
-                .collect::<Vec<Document>>(),
-            None,
-        )
-        .await
-    {
-        Err(e) => {
-            logger.severe(format!(
-                "Error inserting into 'processed' collection: {}",
-                e
-            ));
+#[cfg(test)]
+mod purchases_tests {
+    use super::process_data;
+    use crate::processing::{
+        outbox,
+        test_utils::{config, insert_metadata, logger, sale, setup, tokens},
+    };
+    use futures::stream::TryStreamExt;
+    use mongodb::bson::{doc, Document};
+    use sales_common::{email::InMemoryEmailProvider, repository::SaleDoc};
+
+    #[tokio::test]
+    async fn test_each_sale_is_emailed_once() {
+        let Some((db, repo)) = setup("emailed_once").await else {
+            return;
+        };
+        let (conf, logger) = (config(), logger());
+
+        // one transaction buying two domains with the same metadata
+        repo.sales()
+            .insert_many(
+                [
+                    sale("0x123", "john.stark", "abc"),
+                    sale("0x123", "jane.stark", "abc"),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        insert_metadata(&repo, "abc", "john@example.com").await;
+
+        let provider = InMemoryEmailProvider::default();
+        for _ in 0..3 {
+            process_data(&conf, &repo, &logger, &tokens()).await;
+            outbox::deliver(&conf, &repo, &logger, &provider).await;
         }
-        _ => {}
+
+        assert_eq!(provider.executed_batches().concat().len(), 2);
+        assert_eq!(provider.subscribers()[0].email, "john@example.com");
+        assert_eq!(
+            db.collection::<Document>("processed")
+                .count_documents(None, None)
+                .await
+                .unwrap(),
+            2
+        );
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_projects_sale_fields() {
+        let Some((db, repo)) = setup("purchases_pipeline").await else {
+            return;
+        };
+        repo.sales()
+            .insert_many(
+                [
+                    sale("0x1", "john.stark", "abc"),
+                    sale("0x2", "done.stark", "abc"),
+                    sale("0x3", "nometa.stark", "def"),
+                    sale("0x4", "empty.stark", ""),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        insert_metadata(&repo, "abc", "john@example.com").await;
+        db.collection::<Document>("email_groups")
+            .insert_many(
+                [
+                    doc! { "tx_hash": "0x1", "group": "1" },
+                    doc! { "tx_hash": "0x1", "group": "2" },
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        db.collection::<Document>("processed")
+            .insert_one(doc! { "tx_hash": "0x2", "domain": "done.stark" }, None)
+            .await
+            .unwrap();
+
+        let sales: Vec<SaleDoc> = repo
+            .unprocessed_sales()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].tx_hash, "0x1");
+        assert_eq!(sales[0].domain, "john.stark");
+        assert_eq!(sales[0].price, 10.0);
+        assert_eq!(sales[0].payer, "0x456");
+        assert_eq!(sales[0].expiry, 1731536000);
+        let metadata = repo.decrypt_metadata(&sales[0].metadata[0]).unwrap();
+        assert_eq!(metadata.email, "john@example.com");
+        assert_eq!(sales[0].same_tx_groups, vec!["1", "2"]);
+        db.drop(None).await.unwrap();
     }
 }
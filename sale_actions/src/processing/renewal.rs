@@ -1,290 +1,211 @@
-use super::MetadataDoc;
-use crate::{config::Config, logger::Logger};
+use super::{mark_processed, preferences_token};
+use crate::config::Config;
 use email_address::EmailAddress;
 use futures::stream::StreamExt;
-use mongodb::{
-    bson::{doc, Document},
-    Collection, Database,
+use sales_common::{
+    crypto::TokenSigner,
+    email::EmailRequest,
+    logger::Logger,
+    repository::{Ledger, NotificationKind, NotificationRequest, ReenewalToggledDoc, Repository},
 };
-use reqwest::{header, Client};
-use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ReenewalToggledDoc {
-    pub tx_hash: String,
-    pub domain: String,
-    pub renewer: String,
-    pub allowance: String,
-    pub metadata: Vec<MetadataDoc>,
-    pub same_tx_groups: Vec<String>,
-}
-
-#[derive(Deserialize, Debug)]
-struct ApiResponse {
-    data: Data,
-}
-
-#[derive(Deserialize, Debug)]
-struct Data {
-    id: String,
-    groups: Vec<Group>,
-}
-
-#[derive(Deserialize, Debug)]
-struct Group {
-    id: String,
-}
-
-// Function to create requests for disabling auto-renewal
-fn create_disable_request(data: &Data, base_url: &str, ar_group_id: &str) -> Value {
-    let new_groups = data
-        .groups
-        .iter()
-        .map(|g| g.id.clone())
-        .filter(|id| id != ar_group_id)
-        .collect::<Vec<String>>();
-
-    let url = format!(
-        "{base_url}/subscribers/{id}",
-        base_url = base_url,
-        id = data.id
-    );
-
-    json!({
-        "method": "PUT",
-        "path": &url,
-        "body": { "groups": new_groups }
-    })
-}
+use std::collections::BTreeMap;
 
 // Function to create requests for enabling auto-renewal
-fn create_enable_request(sale: &ReenewalToggledDoc, base_url: &str) -> Value {
-    let groups_params: Vec<String> = sale
-        .same_tx_groups
-        .iter()
-        .map(|group| format!("groups[]={}", group))
-        .collect();
-
-    let url = format!(
-        "{base_url}/subscribers?email={email}&fields[name]={domain}&fields[renewer]={renewer}&{groups}",
-        base_url = base_url,
-        email = &sale.metadata[0].email,
-        domain = &sale.domain,
-        renewer = &sale.renewer,
-        groups = groups_params.join("&")
-    );
+fn create_enable_request(
+    renewal: &ReenewalToggledDoc,
+    ar_group_id: &str,
+    preferences_token: String,
+) -> EmailRequest {
+    let mut groups = renewal.same_tx_groups.clone();
+    if !groups.iter().any(|group| group == ar_group_id) {
+        groups.push(ar_group_id.to_string());
+    }
 
-    json!({
-        "method": "POST",
-        "path": &url
-    })
+    EmailRequest::UpsertSubscriber {
+        email: renewal.metadata[0].email.clone(),
+        fields: BTreeMap::from([
+            ("name".to_string(), renewal.domain.clone()),
+            ("renewer".to_string(), renewal.renewer.clone()),
+            ("preferences_token".to_string(), preferences_token),
+        ]),
+        groups,
+    }
 }
 
-// Function to process batch requests
-async fn process_batch_requests(conf: &Config, logger: &Logger, requests: &[Value]) {
-    let batch_request = json!({
-        "requests": requests
-    });
-
-    let client = Client::new();
-    match client
-        .post("https://api.mailerlite.com/api/v2/batch")
-        .header("X-MailerLite-ApiKey", &conf.email.api_key)
-        .header(header::CONTENT_TYPE, "application/json")
-        .json(&batch_request)
-        .send()
-        .await
-    {
-        Ok(res) => {
-            if !res.status().is_success() {
-                logger.severe(format!(
-                    "Received non-success status from batch request: {}. Response body: {}",
-                    res.status(),
-                    res.text()
-                        .await
-                        .unwrap_or_else(|_| "Failed to retrieve response body".to_string())
-                ));
-            }
-        }
-        Err(e) => {
-            logger.severe(format!("Failed to send batch request: {}", e));
-        }
+// Function to create requests for disabling auto-renewal, the subscriber is looked up by the
+// outbox once the notifications enqueued before it for the email were sent
+fn create_disable_request(renewal: &ReenewalToggledDoc, ar_group_id: &str) -> NotificationRequest {
+    NotificationRequest::RemoveEmailFromGroup {
+        email: renewal.metadata[0].email.clone(),
+        group: ar_group_id.to_string(),
     }
 }
 
-// Adjusted process_data to collect renewals and process in batch
-pub async fn process_data(conf: &Config, db: &Database, logger: &Logger) {
-    let pipeline: Vec<Document> = vec![
-        doc! {
-            "$match": {
-                "meta_hash": { "$exists": true },
-                "tx_hash": { "$exists": true }
-            }
-        },
-        doc! {
-            "$lookup": {
-                "from": "metadata",
-                "let": { "meta_hash": "$meta_hash" },
-                "pipeline": [
-                    doc! {
-                        "$match": {
-                            "$expr": { "$eq": [ "$meta_hash", "$$meta_hash" ] }
-                        }
-                    },
-                    doc! {
-                        "$project": { "_id": 0, "meta_hash": 1 }
-                    }
-                ],
-                "as": "metadata"
-            }
-        },
-        doc! {
-            "$match": {
-                "metadata": { "$ne": [] }
-            }
-        },
-        doc! {
-            "$lookup": {
-                "from": "ar_processed",
-                "let": { "tx_hash": "$tx_hash" },
-                "pipeline": [
-                    doc! {
-                        "$match": {
-                            "$expr": { "$eq": [ "$tx_hash", "$$tx_hash" ] }
-                        }
-                    },
-                    doc! {
-                        "$project": { "_id": 0, "tx_hash": 1 }
-                    }
-                ],
-                "as": "processed_doc"
-            }
-        },
-        doc! {
-            "$match": {
-                "processed_doc": { "$eq": [] }
-            }
-        },
-        doc! {
-            "$lookup": {
-                "from": "email_groups",
-                "let": { "tx_hash": "$tx_hash" },
-                "pipeline": [
-                    doc! {
-                        "$match": {
-                            "$expr": { "$eq": [ "$tx_hash", "$$tx_hash" ] }
-                        }
-                    },
-                    doc! {
-                        "$project": { "_id": 0, "group": 1 }
-                    }
-                ],
-                "as": "same_tx_groups"
-            }
-        },
-        doc! {
-            "$project": {
-                "meta_hash": 1,
-                "tx_hash": 1,
-                "same_tx_groups": {
-                    "$map": {
-                        "input": "$same_tx_groups",
-                        "as": "item",
-                        "in": "$$item.group"
-                    }
-                }
-            }
-        },
-    ];
-
-    let collection: Collection<Document> = db.collection("auto_renew_updates");
-    let mut cursor = collection.aggregate(pipeline, None).await.unwrap();
+// collect auto-renewal toggles and add their notification to the outbox
+pub async fn process_data(conf: &Config, repo: &Repository, logger: &Logger, tokens: &TokenSigner) {
+    let mut renewals = match repo.unprocessed_renewals().await {
+        Ok(renewals) => renewals,
+        Err(e) => {
+            logger.severe(format!("Error while aggregating renewals: {}", e));
+            return;
+        }
+    };
     let mut processed = Vec::new();
-    let mut batch_requests = Vec::new();
-    let batch_size = conf.email.batch_size;
-    let client = Client::new();
 
-    while let Some(result) = cursor.next().await {
+    while let Some(result) = renewals.next().await {
         match result {
-            Ok(document) => match mongodb::bson::from_document::<ReenewalToggledDoc>(document) {
-                Err(e) => {
-                    logger.severe(format!("Error parsing doc in renewal: {}", e));
-                }
-                Ok(renewal_doc) => {
-                    if !EmailAddress::is_valid(&renewal_doc.metadata[0].email) {
-                        logger.local(format!(
-                            "email {} is not valid",
-                            &renewal_doc.metadata[0].email
+            Ok(mut renewal_doc) => {
+                match repo.decrypt_metadata(&renewal_doc.metadata[0]) {
+                    Ok(metadata) => renewal_doc.metadata[0] = metadata,
+                    Err(e) => {
+                        // Not marked as processed so it is retried once the key is configured
+                        logger.severe(format!(
+                            "Error decrypting metadata of renewal of {}: {}",
+                            renewal_doc.domain, e
                         ));
                         continue;
                     }
+                }
+                let key = (renewal_doc.tx_hash.clone(), renewal_doc.domain.clone());
+                // Nothing can be sent to an invalid email, it is not retried
+                if !EmailAddress::is_valid(&renewal_doc.metadata[0].email) {
+                    logger.local(format!(
+                        "email of the renewal of {} in {} is not valid",
+                        renewal_doc.domain, renewal_doc.tx_hash
+                    ));
+                    processed.push(key);
+                    continue;
+                }
 
-                    if renewal_doc.allowance == "0" {
-                        let response = client
-                            .get(&format!(
-                                "{base_url}/subscribers/{email}",
-                                base_url = conf.email.base_url,
-                                email = &renewal_doc.metadata[0].email
-                            ))
-                            .header("X-MailerLite-ApiKey", &conf.email.api_key)
-                            .send()
-                            .await;
-
-                        if let Ok(res) = response {
-                            if let Ok(api_response) = res.json::<ApiResponse>().await {
-                                batch_requests.push(create_disable_request(
-                                    &api_response.data,
-                                    &conf.email.base_url,
-                                    &conf.email.ar_group_id,
-                                ));
-                            } else {
-                                logger.severe(
-                                    "Error parsing response while disabling AR".to_string(),
-                                );
-                            }
-                        } else {
-                            logger.severe("Error sending GET request to disable AR".to_string());
-                        }
-                    } else {
-                        batch_requests
-                            .push(create_enable_request(&renewal_doc, &conf.email.base_url));
-                    }
-
-                    if batch_requests.len() >= batch_size {
-                        process_batch_requests(&conf, &logger, &batch_requests).await;
-                        batch_requests.clear();
-                    }
+                let ar_group_id = &conf.email.ar_group_id;
+                let request = if renewal_doc.allowance == "0" {
+                    create_disable_request(&renewal_doc, ar_group_id)
+                } else {
+                    let token =
+                        preferences_token(conf, repo, tokens, &renewal_doc.metadata[0].email);
+                    NotificationRequest::Email(create_enable_request(
+                        &renewal_doc,
+                        ar_group_id,
+                        token,
+                    ))
+                };
+
+                let (tx_hash, domain) = (&renewal_doc.tx_hash, &renewal_doc.domain);
+                let kind = NotificationKind::Renewal;
+                if let Err(e) = repo
+                    .enqueue_notification(kind, tx_hash, domain, &request)
+                    .await
+                {
+                    logger.severe(format!(
+                        "Error adding renewal of {} to the outbox: {}",
+                        domain, e
+                    ));
+                    continue;
                 }
-            },
+                processed.push(key);
+            }
             Err(e) => {
-                logger.severe(format!("Error while processing: {}", e));
+                logger.severe(format!("Error while processing renewals: {}", e));
             }
         }
     }
 
-    if !batch_requests.is_empty() {
-        process_batch_requests(&conf, &logger, &batch_requests).await;
-    }
+    // Blacklist the processed renewals, their delivery is now tracked by the outbox
+    mark_processed(repo, logger, Ledger::Renewals, processed).await;
+}
 
-    // Blacklist the processed documents
-    let processed_collection: Collection<Document> = db.collection("ar_processed");
-    match processed_collection
-        .insert_many(
-            processed
-                .iter()
-                .map(|tx_hash: &String| doc! { "tx_hash": tx_hash })
-                .collect::<Vec<Document>>(),
-            None,
-        )
-        .await
-    {
-        Err(e) => {
-            logger.severe(format!(
-                "Error inserting into 'processed' collection: {}",
-                e
-            ));
+#[cfg(test)]
+mod renewal_tests {
+    use super::create_enable_request;
+    use crate::processing::test_utils::{insert_metadata, renewal, setup};
+    use futures::stream::TryStreamExt;
+    use mongodb::bson::{doc, Document};
+    use sales_common::{
+        email::EmailRequest,
+        metadata_hash::HashVersion,
+        repository::{MetadataDoc, ReenewalToggledDoc},
+    };
+
+    #[test]
+    fn test_enable_request_adds_auto_renewal_group() {
+        let renewal = ReenewalToggledDoc {
+            tx_hash: "0x123".to_string(),
+            domain: "john.stark".to_string(),
+            renewer: "0x456".to_string(),
+            allowance: "1000".to_string(),
+            metadata: vec![MetadataDoc {
+                meta_hash: "abc".to_string(),
+                email: "john@example.com".to_string(),
+                tax_state: "FR".to_string(),
+                salt: "0x1".to_string(),
+                email_hash: String::new(),
+                hash_version: HashVersion::V1,
+                unverified: false,
+                verify_until: None,
+            }],
+            same_tx_groups: vec!["newsletter".to_string(), "ar_group".to_string()],
+        };
+        match create_enable_request(&renewal, "ar_group", "token".to_string()) {
+            EmailRequest::UpsertSubscriber {
+                email,
+                fields,
+                groups,
+            } => {
+                assert_eq!(email, "john@example.com");
+                assert_eq!(groups, vec!["newsletter", "ar_group"]);
+                assert_eq!(fields["preferences_token"], "token");
+            }
+            request => panic!("unexpected request {:?}", request),
         }
-        _ => {}
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_projects_renewal_fields() {
+        let Some((db, repo)) = setup("renewal_pipeline").await else {
+            return;
+        };
+        repo.auto_renew_updates()
+            .insert_many(
+                [
+                    renewal("0x1", "john.stark", "1000", "abc"),
+                    // disabled, keeps the meta_hash of the previous enabling
+                    renewal("0x2", "jane.stark", "0", "abc"),
+                    renewal("0x3", "done.stark", "1000", "abc"),
+                    renewal("0x4", "nometa.stark", "1000", "def"),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        insert_metadata(&repo, "abc", "john@example.com").await;
+        db.collection::<Document>("email_groups")
+            .insert_one(doc! { "tx_hash": "0x1", "group": "newsletter" }, None)
+            .await
+            .unwrap();
+        db.collection::<Document>("ar_processed")
+            .insert_one(doc! { "tx_hash": "0x3", "domain": "done.stark" }, None)
+            .await
+            .unwrap();
+
+        let mut renewals: Vec<ReenewalToggledDoc> = repo
+            .unprocessed_renewals()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        renewals.sort_by(|a, b| a.domain.cmp(&b.domain));
+
+        assert_eq!(renewals.len(), 2);
+        assert_eq!(renewals[0].domain, "jane.stark");
+        assert_eq!(renewals[0].allowance, "0");
+        assert!(renewals[0].same_tx_groups.is_empty());
+        assert_eq!(renewals[1].domain, "john.stark");
+        assert_eq!(renewals[1].renewer, "0x456");
+        assert_eq!(renewals[1].allowance, "1000");
+        let metadata = repo.decrypt_metadata(&renewals[1].metadata[0]).unwrap();
+        assert_eq!(metadata.email, "john@example.com");
+        assert_eq!(renewals[1].same_tx_groups, vec!["newsletter"]);
+        db.drop(None).await.unwrap();
     }
 }
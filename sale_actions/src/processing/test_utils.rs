@@ -0,0 +1,118 @@
+use crate::config::Config;
+use mongodb::Database;
+use sales_common::{
+    crypto::{Cipher, TokenSigner},
+    logger::Logger,
+    metadata_hash::HashVersion,
+    repository::{migrations, IndexedRenewalDoc, IndexedSaleDoc, MetadataDoc, Repository},
+};
+
+use sales_common::repository::test_utils::test_db;
+
+pub fn config() -> Config {
+    toml::from_str(
+        r#"
+        [general]
+        check_delay = 10
+        renewal_sync = true
+
+        [email]
+        provider = "memory"
+        api_version = "connect"
+        base_url = "http://localhost"
+        api_key = "xxx"
+        ar_group_id = "ar_group"
+        batch_size = 100
+
+        [outbox]
+        max_attempts = 8
+        base_delay = 60
+        max_delay = 3600
+
+        [newsletter]
+        confirmation_group_id = "confirmation_group"
+        token_key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+        preferences_validity = 2592000
+
+        [database]
+        name = "test"
+        connection_string = "mongodb://localhost:27017"
+
+        [encryption]
+        current_key = "k1"
+        index_key = "aW5kZXhpbmRleGluZGV4aW5kZXhpbmRleGluZGV4MTI="
+        [encryption.keys]
+        k1 = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="
+
+        [watchtower]
+        enabled = false
+        endpoint = "http://localhost"
+        app_id = "xxx"
+        token = "xxx"
+        [watchtower.types]
+        info = "info"
+        warning = "warning"
+        severe = "severe"
+        "#,
+    )
+    .unwrap()
+}
+
+pub fn logger() -> Logger {
+    Logger::new(&config().watchtower)
+}
+
+pub fn tokens() -> TokenSigner {
+    TokenSigner::new(&config().newsletter.token_key).unwrap()
+}
+
+pub fn repo(db: &Database) -> Repository {
+    Repository::new(db.clone(), Cipher::new(&config().encryption).unwrap())
+}
+
+// Database of a test with the migrations applied, None when no test database is configured
+pub async fn setup(name: &str) -> Option<(Database, Repository)> {
+    let db = test_db(name).await?;
+    let repo = repo(&db);
+    migrations::run(&repo).await.unwrap();
+    Some((db, repo))
+}
+
+// Sale as the indexer stores it
+pub fn sale(tx_hash: &str, domain: &str, meta_hash: &str) -> IndexedSaleDoc {
+    IndexedSaleDoc {
+        tx_hash: tx_hash.to_string(),
+        meta_hash: meta_hash.to_string(),
+        domain: domain.to_string(),
+        price: 10.0,
+        payer: "0x456".to_string(),
+        timestamp: 1700000000,
+        expiry: 1731536000,
+    }
+}
+
+// Auto-renewal toggle as the indexer stores it
+pub fn renewal(tx_hash: &str, domain: &str, allowance: &str, meta_hash: &str) -> IndexedRenewalDoc {
+    IndexedRenewalDoc {
+        tx_hash: tx_hash.to_string(),
+        domain: domain.to_string(),
+        renewer: "0x456".to_string(),
+        allowance: allowance.to_string(),
+        meta_hash: Some(meta_hash.to_string()),
+    }
+}
+
+// Stores metadata the way the API does, encrypted
+pub async fn insert_metadata(repo: &Repository, meta_hash: &str, email: &str) {
+    let metadata = MetadataDoc {
+        meta_hash: meta_hash.to_string(),
+        email: email.to_string(),
+        tax_state: "FR".to_string(),
+        salt: "0x1".to_string(),
+        email_hash: String::new(),
+        hash_version: HashVersion::V1,
+        unverified: false,
+        verify_until: None,
+    };
+    repo.upsert_metadata(&metadata).await.unwrap();
+}
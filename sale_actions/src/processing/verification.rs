@@ -0,0 +1,17 @@
+use sales_common::{logger::Logger, repository::Repository};
+
+// Metadata submitted before its sale was indexed is unverified and not mailed, and so are the
+// email groups submitted before their transaction was indexed. Verify the ones whose sale or
+// transaction arrived since.
+pub async fn process_data(repo: &Repository, logger: &Logger) {
+    match repo.verify_pending_metadata().await {
+        Ok(0) => (),
+        Ok(verified) => logger.info(format!("verified {} metadata", verified)),
+        Err(e) => logger.severe(format!("Error while verifying metadata: {}", e)),
+    }
+    match repo.verify_pending_email_groups().await {
+        Ok(0) => (),
+        Ok(verified) => logger.info(format!("verified {} email groups", verified)),
+        Err(e) => logger.severe(format!("Error while verifying email groups: {}", e)),
+    }
+}
@@ -0,0 +1,74 @@
+use serde::{self, de::DeserializeOwned, Deserialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+pub_struct!(Clone, Deserialize; Database {
+    name: String,
+    connection_string: String,
+});
+
+// Keys encrypting personal data, base64 encoded 32 bytes. New values are encrypted with
+// `current_key`, the other keys are kept to decrypt values until they are rotated.
+pub_struct!(Clone, Deserialize; Encryption {
+    current_key: String,
+    keys: HashMap<String, String>,
+    index_key: String,
+});
+
+pub_struct!(Clone, Deserialize; WatchtowerTypes {
+    info: String,
+    warning: String,
+    severe: String,
+});
+
+pub_struct!(Clone, Deserialize; Watchtower {
+    enabled : bool,
+    endpoint: String,
+    app_id: String,
+    token: String,
+    types: WatchtowerTypes,
+});
+
+// `<binary> migrate [config path]` applies the database migrations and exits
+pub const MIGRATE_COMMAND: &str = "migrate";
+// `<binary> rotate-keys [config path]` re-encrypts the personal data with the current key and exits
+pub const ROTATE_KEYS_COMMAND: &str = "rotate-keys";
+const COMMANDS: [&str; 2] = [MIGRATE_COMMAND, ROTATE_KEYS_COMMAND];
+
+fn is_command(command: &str) -> bool {
+    env::args().nth(1).as_deref() == Some(command)
+}
+
+pub fn is_migrate_command() -> bool {
+    is_command(MIGRATE_COMMAND)
+}
+
+pub fn is_rotate_keys_command() -> bool {
+    is_command(ROTATE_KEYS_COMMAND)
+}
+
+// Loads the binary specific config from the path given as first argument (config.toml by default)
+pub fn load<T: DeserializeOwned>() -> T {
+    let args: Vec<String> = env::args()
+        .enumerate()
+        .filter(|(index, arg)| *index != 1 || !COMMANDS.contains(&arg.as_str()))
+        .map(|(_, arg)| arg)
+        .collect();
+    let config_path = if args.len() <= 1 {
+        "config.toml"
+    } else {
+        args.get(1).unwrap()
+    };
+    let file_contents = fs::read_to_string(config_path);
+    if file_contents.is_err() {
+        panic!("error: unable to read file with path \"{}\"", config_path);
+    }
+
+    match toml::from_str(file_contents.unwrap().as_str()) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            panic!("error: unable to deserialize config. {}", err);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use crate::config::Encryption;
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::collections::HashMap;
+use std::fmt;
+
+// Field level encryption of the personal data we store. A value is sealed with AES-256-GCM under
+// the current key and stored as "enc:<key id>:<base64 of nonce and ciphertext>", the name of the
+// field is authenticated so a ciphertext can't be moved to another field. Older keys are kept to
+// read values until they are re-encrypted. Lookups go through a keyed hash of the value.
+
+const PREFIX: &str = "enc:";
+
+#[derive(Debug, PartialEq)]
+pub enum CryptoError {
+    InvalidKey(String),
+    UnknownKey(String),
+    Malformed,
+    Decryption,
+    Encryption,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::InvalidKey(id) => write!(f, "key {} is not 32 base64 encoded bytes", id),
+            CryptoError::UnknownKey(id) => write!(f, "unknown key {}", id),
+            CryptoError::Malformed => write!(f, "malformed encrypted value"),
+            CryptoError::Decryption => write!(f, "unable to decrypt value"),
+            CryptoError::Encryption => write!(f, "unable to encrypt value"),
+        }
+    }
+}
+
+pub struct Cipher {
+    current_key: String,
+    keys: HashMap<String, LessSafeKey>,
+    index_key: hmac::Key,
+    rng: SystemRandom,
+}
+
+// The keys are never printed
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher")
+            .field("current_key", &self.current_key)
+            .finish_non_exhaustive()
+    }
+}
+
+fn decode_key(id: &str, encoded: &str) -> Result<Vec<u8>, CryptoError> {
+    match STANDARD.decode(encoded) {
+        Ok(key) if key.len() == 32 => Ok(key),
+        _ => Err(CryptoError::InvalidKey(id.to_string())),
+    }
+}
+
+impl Cipher {
+    pub fn new(conf: &Encryption) -> Result<Self, CryptoError> {
+        let mut keys = HashMap::new();
+        for (id, encoded) in &conf.keys {
+            if id.contains(':') {
+                return Err(CryptoError::InvalidKey(id.clone()));
+            }
+            let key = UnboundKey::new(&AES_256_GCM, &decode_key(id, encoded)?)
+                .map_err(|_| CryptoError::InvalidKey(id.clone()))?;
+            keys.insert(id.clone(), LessSafeKey::new(key));
+        }
+        if !keys.contains_key(&conf.current_key) {
+            return Err(CryptoError::UnknownKey(conf.current_key.clone()));
+        }
+        Ok(Cipher {
+            current_key: conf.current_key.clone(),
+            keys,
+            index_key: hmac::Key::new(
+                hmac::HMAC_SHA256,
+                &decode_key("index_key", &conf.index_key)?,
+            ),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn encrypt(&self, field: &str, plaintext: &str) -> Result<String, CryptoError> {
+        let key = &self.keys[&self.current_key];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| CryptoError::Encryption)?;
+        let mut sealed = plaintext.as_bytes().to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(field.as_bytes()),
+            &mut sealed,
+        )
+        .map_err(|_| CryptoError::Encryption)?;
+        let mut payload = nonce.to_vec();
+        payload.extend(sealed);
+        Ok(format!(
+            "{}{}:{}",
+            PREFIX,
+            self.current_key,
+            STANDARD.encode(payload)
+        ))
+    }
+
+    // Values stored before encryption was enabled are returned as is
+    pub fn decrypt(&self, field: &str, value: &str) -> Result<String, CryptoError> {
+        let Some(encrypted) = value.strip_prefix(PREFIX) else {
+            return Ok(value.to_string());
+        };
+        let (id, encoded) = encrypted.split_once(':').ok_or(CryptoError::Malformed)?;
+        let key = self
+            .keys
+            .get(id)
+            .ok_or_else(|| CryptoError::UnknownKey(id.to_string()))?;
+        let mut payload = STANDARD
+            .decode(encoded)
+            .map_err(|_| CryptoError::Malformed)?;
+        if payload.len() < NONCE_LEN {
+            return Err(CryptoError::Malformed);
+        }
+        let mut sealed = payload.split_off(NONCE_LEN);
+        let nonce =
+            Nonce::try_assume_unique_for_key(&payload).map_err(|_| CryptoError::Malformed)?;
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(field.as_bytes()), &mut sealed)
+            .map_err(|_| CryptoError::Decryption)?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| CryptoError::Decryption)
+    }
+
+    // Whether the value is in plaintext or sealed with an older key
+    pub fn needs_rotation(&self, value: &str) -> bool {
+        match value.strip_prefix(PREFIX).and_then(|v| v.split_once(':')) {
+            Some((id, _)) => id != self.current_key,
+            None => true,
+        }
+    }
+
+    // Deterministic keyed hash, to look a value up without storing it in plaintext
+    pub fn blind_index(&self, value: &str) -> String {
+        hex::encode(hmac::sign(&self.index_key, value.as_bytes()).as_ref())
+    }
+}
+
+// Signs tokens handed to users, such as unsubscribe links. A token is "<subject>.<signature>",
+// the signature covers its purpose so a token can't be used for something else.
+pub struct TokenSigner {
+    key: hmac::Key,
+}
+
+impl TokenSigner {
+    pub fn new(encoded_key: &str) -> Result<Self, CryptoError> {
+        Ok(TokenSigner {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &decode_key("token_key", encoded_key)?),
+        })
+    }
+
+    fn message(purpose: &str, subject: &str) -> String {
+        format!("{}:{}", purpose, subject)
+    }
+
+    pub fn sign(&self, purpose: &str, subject: &str) -> String {
+        let signature = hmac::sign(&self.key, Self::message(purpose, subject).as_bytes());
+        format!("{}.{}", subject, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    }
+
+    // Returns the subject of a valid token
+    pub fn verify(&self, purpose: &str, token: &str) -> Option<String> {
+        let (subject, signature) = token.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac::verify(
+            &self.key,
+            Self::message(purpose, subject).as_bytes(),
+            &signature,
+        )
+        .ok()?;
+        Some(subject.to_string())
+    }
+
+    // Token of `subject` valid until `expires_at`, a unix timestamp
+    pub fn sign_until(&self, purpose: &str, subject: &str, expires_at: i64) -> String {
+        self.sign(purpose, &format!("{}:{}", subject, expires_at))
+    }
+
+    // Returns the subject of a valid token not yet expired at `now`
+    pub fn verify_until(&self, purpose: &str, token: &str, now: i64) -> Option<String> {
+        let subject = self.verify(purpose, token)?;
+        let (subject, expires_at) = subject.rsplit_once(':')?;
+        let expires_at = expires_at.parse::<i64>().ok()?;
+        (now < expires_at).then(|| subject.to_string())
+    }
+}
+
+// Purpose of the signed tokens of the preference center links
+pub const PREFERENCES: &str = "preferences";
+
+// Token of the preference center link of an email, identified through its keyed hash. Both
+// binaries issue it, with the same token key.
+pub fn preferences_token(
+    tokens: &TokenSigner,
+    cipher: &Cipher,
+    email: &str,
+    expires_at: i64,
+) -> String {
+    tokens.sign_until(PREFERENCES, &cipher.blind_index(email), expires_at)
+}
+
+#[cfg(test)]
+mod crypto_tests {
+    use super::{Cipher, CryptoError, TokenSigner};
+    use crate::repository::test_utils::encryption;
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = Cipher::new(&encryption("k1")).unwrap();
+        let encrypted = cipher.encrypt("email", "john@example.com").unwrap();
+        assert!(encrypted.starts_with("enc:k1:"));
+        assert!(!encrypted.contains("john"));
+        assert_ne!(
+            encrypted,
+            cipher.encrypt("email", "john@example.com").unwrap()
+        );
+        assert_eq!(
+            cipher.decrypt("email", &encrypted).unwrap(),
+            "john@example.com"
+        );
+    }
+
+    #[test]
+    fn test_ciphertext_is_bound_to_its_field() {
+        let cipher = Cipher::new(&encryption("k1")).unwrap();
+        let encrypted = cipher.encrypt("email", "john@example.com").unwrap();
+        assert_eq!(
+            cipher.decrypt("tax_state", &encrypted),
+            Err(CryptoError::Decryption)
+        );
+    }
+
+    #[test]
+    fn test_rotation_keeps_old_values_readable() {
+        let old = Cipher::new(&encryption("k1")).unwrap();
+        let new = Cipher::new(&encryption("k2")).unwrap();
+        let encrypted = old.encrypt("email", "john@example.com").unwrap();
+        assert!(new.needs_rotation(&encrypted));
+        assert!(new.needs_rotation("john@example.com"));
+        assert_eq!(
+            new.decrypt("email", &encrypted).unwrap(),
+            "john@example.com"
+        );
+        assert!(!new.needs_rotation(&new.encrypt("email", "john@example.com").unwrap()));
+    }
+
+    #[test]
+    fn test_plaintext_is_read_as_is() {
+        let cipher = Cipher::new(&encryption("k1")).unwrap();
+        assert_eq!(
+            cipher.decrypt("email", "john@example.com").unwrap(),
+            "john@example.com"
+        );
+        assert_eq!(
+            cipher.decrypt("email", "enc:k1"),
+            Err(CryptoError::Malformed)
+        );
+        assert_eq!(
+            cipher.decrypt("email", "enc:k3:AAAA"),
+            Err(CryptoError::UnknownKey("k3".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_blind_index_is_deterministic() {
+        let cipher = Cipher::new(&encryption("k1")).unwrap();
+        assert_eq!(
+            cipher.blind_index("john@example.com"),
+            Cipher::new(&encryption("k2"))
+                .unwrap()
+                .blind_index("john@example.com")
+        );
+        assert_ne!(
+            cipher.blind_index("john@example.com"),
+            cipher.blind_index("jane@example.com")
+        );
+    }
+
+    #[test]
+    fn test_invalid_keys_are_rejected() {
+        let mut conf = encryption("k3");
+        assert_eq!(
+            Cipher::new(&conf).err(),
+            Some(CryptoError::UnknownKey("k3".to_string()))
+        );
+        conf.current_key = "k1".to_string();
+        conf.keys.insert("k1".to_string(), "c2hvcnQ=".to_string());
+        assert_eq!(
+            Cipher::new(&conf).err(),
+            Some(CryptoError::InvalidKey("k1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_tokens_are_bound_to_their_purpose() {
+        let signer = TokenSigner::new("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        let token = signer.sign("unsubscribe", "abc");
+        assert_eq!(
+            signer.verify("unsubscribe", &token),
+            Some("abc".to_string())
+        );
+        assert_eq!(signer.verify("confirm", &token), None);
+        assert_eq!(
+            signer.verify("unsubscribe", &token.replace("abc", "abd")),
+            None
+        );
+        assert_eq!(signer.verify("unsubscribe", "abc"), None);
+        let other = TokenSigner::new("ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=").unwrap();
+        assert_eq!(other.verify("unsubscribe", &token), None);
+    }
+
+    #[test]
+    fn test_tokens_expire() {
+        let signer = TokenSigner::new("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=").unwrap();
+        let token = signer.sign_until("preferences", "abc", 1000);
+        assert_eq!(
+            signer.verify_until("preferences", &token, 999),
+            Some("abc".to_string())
+        );
+        assert_eq!(signer.verify_until("preferences", &token, 1000), None);
+        assert_eq!(
+            signer.verify_until("preferences", &token.replace("1000", "2000"), 999),
+            None
+        );
+        // tokens without an expiry are rejected
+        let token = signer.sign("preferences", "abc");
+        assert_eq!(signer.verify_until("preferences", &token, 999), None);
+    }
+}
@@ -0,0 +1,659 @@
+use super::{BatchOutcome, EmailError, EmailProvider, EmailRequest, Subscriber};
+use async_trait::async_trait;
+use reqwest::{header, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+// MailerLite API flavour, selected by the `api_version` key of `[email]`. The configured
+// `base_url` must be the root of that version (e.g. https://connect.mailerlite.com/api or
+// https://api.mailerlite.com/api/v2), every URL, batch path and auth header derives from it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiVersion {
+    Classic,
+    Connect,
+}
+
+pub struct MailerLite {
+    version: ApiVersion,
+    base_url: String,
+    api_key: String,
+    client: Client,
+}
+
+// A single HTTP call against the API, relative to the base url
+#[derive(Debug, PartialEq)]
+struct Call {
+    method: Method,
+    endpoint: String,
+    body: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConnectSubscriberResponse {
+    data: ConnectSubscriber,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConnectSubscriber {
+    id: Value,
+    email: String,
+    #[serde(default)]
+    fields: BTreeMap<String, Value>,
+    #[serde(default)]
+    groups: Vec<GroupData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClassicSubscriber {
+    email: String,
+    #[serde(default)]
+    fields: Vec<ClassicField>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ClassicField {
+    key: String,
+    value: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct GroupData {
+    id: Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchResponse {
+    code: u16,
+    #[serde(default)]
+    body: Value,
+}
+
+// Connect wraps the responses in an object with totals, classic returns them as an array
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum BatchResponses {
+    Wrapped { responses: Vec<BatchResponse> },
+    List(Vec<BatchResponse>),
+}
+
+// Ids are strings on connect and numbers on classic
+fn value_to_string(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(value) => Some(value),
+        value => Some(value.to_string()),
+    }
+}
+
+impl MailerLite {
+    pub fn new(version: ApiVersion, base_url: &str, api_key: &str) -> Self {
+        MailerLite {
+            version,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+            client: Client::new(),
+        }
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}/{}", self.base_url, endpoint)
+    }
+
+    // Path of an endpoint inside a batch: connect expects it relative to the host without a
+    // leading slash ("api/subscribers"), classic expects an absolute one ("/api/v2/subscribers")
+    fn batch_path(&self, endpoint: &str) -> String {
+        let base_path = match Url::parse(&self.base_url) {
+            Ok(url) => url.path().trim_end_matches('/').to_string(),
+            Err(_) => String::new(),
+        };
+        match self.version {
+            ApiVersion::Connect => format!("{}/{}", base_path.trim_start_matches('/'), endpoint),
+            ApiVersion::Classic => format!("{}/{}", base_path, endpoint),
+        }
+    }
+
+    fn request(&self, method: Method, endpoint: &str) -> RequestBuilder {
+        let builder = self
+            .client
+            .request(method, self.url(endpoint))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::ACCEPT, "application/json");
+        match self.version {
+            ApiVersion::Connect => {
+                builder.header(header::AUTHORIZATION, format!("Bearer {}", self.api_key))
+            }
+            ApiVersion::Classic => builder.header("X-MailerLite-ApiKey", &self.api_key),
+        }
+    }
+
+    async fn send(&self, builder: RequestBuilder) -> Result<Response, EmailError> {
+        let res = builder
+            .send()
+            .await
+            .map_err(|err| EmailError::Request(err.to_string()))?;
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = ["retry-after", "x-ratelimit-retry-after"]
+                .iter()
+                .find_map(|name| res.headers().get(*name)?.to_str().ok()?.parse().ok());
+            return Err(EmailError::RateLimited { retry_after });
+        }
+        if !res.status().is_success() {
+            return Err(EmailError::Status {
+                status: res.status().as_u16(),
+                body: res
+                    .text()
+                    .await
+                    .unwrap_or_else(|_| "Failed to retrieve response body".to_string()),
+            });
+        }
+        Ok(res)
+    }
+
+    async fn send_call(&self, call: Call) -> Result<Response, EmailError> {
+        let builder = self.request(call.method, &call.endpoint);
+        self.send(match call.body {
+            Some(body) => builder.json(&body),
+            None => builder,
+        })
+        .await
+    }
+
+    // Translates a request to the calls of the configured version. Classic has no groups
+    // parameter on subscribers, so an upsert becomes one call per group.
+    fn calls(&self, request: &EmailRequest) -> Vec<Call> {
+        match (self.version, request) {
+            (
+                ApiVersion::Connect,
+                EmailRequest::UpsertSubscriber {
+                    email,
+                    fields,
+                    groups,
+                },
+            ) => vec![Call {
+                method: Method::POST,
+                endpoint: "subscribers".to_string(),
+                body: Some(json!({ "email": email, "fields": fields, "groups": groups })),
+            }],
+            (
+                ApiVersion::Classic,
+                EmailRequest::UpsertSubscriber {
+                    email,
+                    fields,
+                    groups,
+                },
+            ) => {
+                let body = json!({ "email": email, "fields": fields });
+                if groups.is_empty() {
+                    return vec![Call {
+                        method: Method::POST,
+                        endpoint: "subscribers".to_string(),
+                        body: Some(body),
+                    }];
+                }
+                groups
+                    .iter()
+                    .map(|group| Call {
+                        method: Method::POST,
+                        endpoint: format!("groups/{}/subscribers", group),
+                        body: Some(body.clone()),
+                    })
+                    .collect()
+            }
+            (
+                ApiVersion::Connect,
+                EmailRequest::AddToGroup {
+                    subscriber_id,
+                    group,
+                },
+            ) => vec![Call {
+                method: Method::POST,
+                endpoint: format!("subscribers/{}/groups/{}", subscriber_id, group),
+                body: None,
+            }],
+            (
+                ApiVersion::Classic,
+                EmailRequest::AddToGroup {
+                    subscriber_id,
+                    group,
+                },
+            ) => vec![Call {
+                method: Method::POST,
+                endpoint: format!("groups/{}/subscribers", group),
+                body: Some(json!({ "email": subscriber_id })),
+            }],
+            (
+                ApiVersion::Connect,
+                EmailRequest::RemoveFromGroup {
+                    subscriber_id,
+                    group,
+                },
+            ) => vec![Call {
+                method: Method::DELETE,
+                endpoint: format!("subscribers/{}/groups/{}", subscriber_id, group),
+                body: None,
+            }],
+            (
+                ApiVersion::Classic,
+                EmailRequest::RemoveFromGroup {
+                    subscriber_id,
+                    group,
+                },
+            ) => vec![Call {
+                method: Method::DELETE,
+                endpoint: format!(
+                    "groups/{}/subscribers/{}",
+                    group,
+                    urlencoding::encode(subscriber_id)
+                ),
+                body: None,
+            }],
+            (ApiVersion::Connect, EmailRequest::ForgetSubscriber { subscriber_id }) => {
+                vec![Call {
+                    method: Method::POST,
+                    endpoint: format!("subscribers/{}/forget", subscriber_id),
+                    body: None,
+                }]
+            }
+            // Classic can't delete subscribers, unsubscribing them is the closest it offers
+            (ApiVersion::Classic, EmailRequest::ForgetSubscriber { subscriber_id }) => {
+                vec![Call {
+                    method: Method::PUT,
+                    endpoint: format!("subscribers/{}", urlencoding::encode(subscriber_id)),
+                    body: Some(json!({ "type": "unsubscribed" })),
+                }]
+            }
+        }
+    }
+
+    fn batch_entry(&self, call: Call) -> Value {
+        let mut entry = json!({
+            "method": call.method.as_str(),
+            "path": self.batch_path(&call.endpoint),
+        });
+        if let Some(body) = call.body {
+            entry["body"] = body;
+        }
+        entry
+    }
+
+    async fn execute(&self, request: EmailRequest) -> Result<(), EmailError> {
+        for call in self.calls(&request) {
+            self.send_call(call).await?;
+        }
+        Ok(())
+    }
+
+    async fn find_connect_subscriber(&self, email: &str) -> Result<Subscriber, EmailError> {
+        let res = self
+            .send(self.request(
+                Method::GET,
+                &format!("subscribers/{}", urlencoding::encode(email)),
+            ))
+            .await?;
+        let data = res
+            .json::<ConnectSubscriberResponse>()
+            .await
+            .map_err(|err| EmailError::Parse(err.to_string()))?
+            .data;
+
+        Ok(Subscriber {
+            id: value_to_string(data.id).unwrap_or_default(),
+            email: data.email,
+            fields: data
+                .fields
+                .into_iter()
+                .filter_map(|(name, value)| Some((name, value_to_string(value)?)))
+                .collect(),
+            groups: data
+                .groups
+                .into_iter()
+                .filter_map(|group| value_to_string(group.id))
+                .collect(),
+        })
+    }
+
+    // Classic identifies subscribers by email on every endpoint we use, so the email is
+    // returned as the subscriber id
+    async fn find_classic_subscriber(&self, email: &str) -> Result<Subscriber, EmailError> {
+        let endpoint = format!("subscribers/{}", urlencoding::encode(email));
+        let data = self
+            .send(self.request(Method::GET, &endpoint))
+            .await?
+            .json::<ClassicSubscriber>()
+            .await
+            .map_err(|err| EmailError::Parse(err.to_string()))?;
+        let groups = self
+            .send(self.request(Method::GET, &format!("{}/groups", endpoint)))
+            .await?
+            .json::<Vec<GroupData>>()
+            .await
+            .map_err(|err| EmailError::Parse(err.to_string()))?;
+
+        Ok(Subscriber {
+            id: data.email.clone(),
+            email: data.email,
+            fields: data
+                .fields
+                .into_iter()
+                .filter_map(|field| Some((field.key, value_to_string(field.value)?)))
+                .collect(),
+            groups: groups
+                .into_iter()
+                .filter_map(|group| value_to_string(group.id))
+                .collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailProvider for MailerLite {
+    async fn upsert_subscriber(
+        &self,
+        email: &str,
+        fields: &BTreeMap<String, String>,
+        groups: &[String],
+    ) -> Result<(), EmailError> {
+        self.execute(EmailRequest::UpsertSubscriber {
+            email: email.to_string(),
+            fields: fields.clone(),
+            groups: groups.to_vec(),
+        })
+        .await
+    }
+
+    async fn add_to_group(&self, subscriber_id: &str, group: &str) -> Result<(), EmailError> {
+        self.execute(EmailRequest::AddToGroup {
+            subscriber_id: subscriber_id.to_string(),
+            group: group.to_string(),
+        })
+        .await
+    }
+
+    async fn remove_from_group(&self, subscriber_id: &str, group: &str) -> Result<(), EmailError> {
+        self.execute(EmailRequest::RemoveFromGroup {
+            subscriber_id: subscriber_id.to_string(),
+            group: group.to_string(),
+        })
+        .await
+    }
+
+    async fn find_subscriber(&self, email: &str) -> Result<Option<Subscriber>, EmailError> {
+        let subscriber = match self.version {
+            ApiVersion::Connect => self.find_connect_subscriber(email).await,
+            ApiVersion::Classic => self.find_classic_subscriber(email).await,
+        };
+        match subscriber {
+            Ok(subscriber) => Ok(Some(subscriber)),
+            Err(EmailError::Status { status, .. }) if status == StatusCode::NOT_FOUND.as_u16() => {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn forget_subscriber(&self, subscriber_id: &str) -> Result<(), EmailError> {
+        self.execute(EmailRequest::ForgetSubscriber {
+            subscriber_id: subscriber_id.to_string(),
+        })
+        .await
+    }
+
+    async fn execute_batch(&self, requests: &[EmailRequest]) -> Result<BatchOutcome, EmailError> {
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // A request can span several calls, remember which request each entry belongs to
+        let mut owners = Vec::new();
+        let mut entries = Vec::new();
+        for (index, request) in requests.iter().enumerate() {
+            for call in self.calls(request) {
+                owners.push(index);
+                entries.push(self.batch_entry(call));
+            }
+        }
+
+        let responses = match self
+            .send(
+                self.request(Method::POST, "batch")
+                    .json(&json!({ "requests": entries })),
+            )
+            .await?
+            .json::<BatchResponses>()
+            .await
+            .map_err(|err| EmailError::Parse(err.to_string()))?
+        {
+            BatchResponses::Wrapped { responses } => responses,
+            BatchResponses::List(responses) => responses,
+        };
+        if responses.len() != owners.len() {
+            return Err(EmailError::Parse(format!(
+                "expected {} batch responses, received {}",
+                owners.len(),
+                responses.len()
+            )));
+        }
+
+        let mut outcome: BatchOutcome = requests.iter().map(|_| Ok(())).collect();
+        for (owner, response) in owners.into_iter().zip(responses) {
+            if !(200..300).contains(&response.code) && outcome[owner].is_ok() {
+                outcome[owner] = Err(EmailError::Status {
+                    status: response.code,
+                    body: response.body.to_string(),
+                });
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod mailerlite_tests {
+    use super::{ApiVersion, EmailError, EmailProvider, EmailRequest, MailerLite};
+    use mockito::Matcher;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    fn sale_request() -> EmailRequest {
+        EmailRequest::UpsertSubscriber {
+            email: "john@example.com".to_string(),
+            fields: BTreeMap::from([("name".to_string(), "john.stark".to_string())]),
+            groups: vec!["1".to_string(), "2".to_string()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_batch_uses_bearer_and_relative_paths() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/api/batch")
+            .match_header("authorization", "Bearer key")
+            .match_body(Matcher::Json(json!({
+                "requests": [
+                    {
+                        "method": "POST",
+                        "path": "api/subscribers",
+                        "body": {
+                            "email": "john@example.com",
+                            "fields": { "name": "john.stark" },
+                            "groups": ["1", "2"]
+                        }
+                    },
+                    { "method": "DELETE", "path": "api/subscribers/42/groups/7" }
+                ]
+            })))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "total": 2,
+                    "successful": 2,
+                    "failed": 0,
+                    "responses": [{ "code": 200, "body": {} }, { "code": 204, "body": {} }]
+                })
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let provider =
+            MailerLite::new(ApiVersion::Connect, &format!("{}/api", server.url()), "key");
+        let requests = [
+            sale_request(),
+            EmailRequest::RemoveFromGroup {
+                subscriber_id: "42".to_string(),
+                group: "7".to_string(),
+            },
+        ];
+        let outcome = provider.execute_batch(&requests).await.unwrap();
+        assert!(outcome.iter().all(|result| result.is_ok()));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_classic_batch_uses_api_key_and_group_paths() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/api/v2/batch")
+            .match_header("x-mailerlite-apikey", "key")
+            .match_body(Matcher::Json(json!({
+                "requests": [
+                    {
+                        "method": "POST",
+                        "path": "/api/v2/groups/1/subscribers",
+                        "body": { "email": "john@example.com", "fields": { "name": "john.stark" } }
+                    },
+                    {
+                        "method": "POST",
+                        "path": "/api/v2/groups/2/subscribers",
+                        "body": { "email": "john@example.com", "fields": { "name": "john.stark" } }
+                    }
+                ]
+            })))
+            .with_status(200)
+            .with_body(
+                json!([{ "code": 200, "body": {} }, { "code": 200, "body": {} }]).to_string(),
+            )
+            .create_async()
+            .await;
+
+        let provider = MailerLite::new(
+            ApiVersion::Classic,
+            &format!("{}/api/v2", server.url()),
+            "key",
+        );
+        let outcome = provider.execute_batch(&[sale_request()]).await.unwrap();
+        assert!(outcome.iter().all(|result| result.is_ok()));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_failed_requests() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/api/v2/batch")
+            .with_status(200)
+            .with_body(
+                json!([
+                    { "code": 200, "body": {} },
+                    { "code": 422, "body": { "message": "invalid email" } },
+                    { "code": 200, "body": {} }
+                ])
+                .to_string(),
+            )
+            .create_async()
+            .await;
+
+        let provider = MailerLite::new(
+            ApiVersion::Classic,
+            &format!("{}/api/v2", server.url()),
+            "key",
+        );
+        // the first request spans two calls and the second of them failed
+        let requests = [
+            sale_request(),
+            EmailRequest::RemoveFromGroup {
+                subscriber_id: "jane@example.com".to_string(),
+                group: "7".to_string(),
+            },
+        ];
+        let outcome = provider.execute_batch(&requests).await.unwrap();
+        assert!(matches!(
+            outcome[0],
+            Err(EmailError::Status { status: 422, .. })
+        ));
+        assert!(outcome[1].is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_find_subscriber_not_found() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/subscribers/john%40example.com")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let provider =
+            MailerLite::new(ApiVersion::Connect, &format!("{}/api", server.url()), "key");
+        assert!(matches!(
+            provider.find_subscriber("john@example.com").await,
+            Ok(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_forget_subscriber() {
+        let mut server = mockito::Server::new_async().await;
+        let connect = server
+            .mock("POST", "/api/subscribers/42/forget")
+            .match_header("authorization", "Bearer key")
+            .with_status(200)
+            .create_async()
+            .await;
+        let classic = server
+            .mock("PUT", "/api/v2/subscribers/john%40example.com")
+            .match_body(Matcher::Json(json!({ "type": "unsubscribed" })))
+            .with_status(200)
+            .create_async()
+            .await;
+
+        MailerLite::new(ApiVersion::Connect, &format!("{}/api", server.url()), "key")
+            .forget_subscriber("42")
+            .await
+            .unwrap();
+        MailerLite::new(
+            ApiVersion::Classic,
+            &format!("{}/api/v2", server.url()),
+            "key",
+        )
+        .forget_subscriber("john@example.com")
+        .await
+        .unwrap();
+        connect.assert_async().await;
+        classic.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_are_reported() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("POST", "/api/subscribers")
+            .with_status(429)
+            .with_header("retry-after", "30")
+            .create_async()
+            .await;
+
+        let provider =
+            MailerLite::new(ApiVersion::Connect, &format!("{}/api", server.url()), "key");
+        assert!(matches!(
+            provider
+                .upsert_subscriber("john@example.com", &BTreeMap::new(), &[])
+                .await,
+            Err(EmailError::RateLimited {
+                retry_after: Some(30)
+            })
+        ));
+    }
+}
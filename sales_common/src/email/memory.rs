@@ -0,0 +1,127 @@
+use super::{BatchOutcome, EmailError, EmailProvider, EmailRequest, Subscriber};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+// Provider keeping subscribers in memory, used for local runs and tests. Subscriber ids are
+// their email addresses.
+#[derive(Default)]
+pub struct InMemoryEmailProvider {
+    subscribers: Mutex<HashMap<String, Subscriber>>,
+    batches: Mutex<Vec<Vec<EmailRequest>>>,
+}
+
+impl InMemoryEmailProvider {
+    pub fn subscribers(&self) -> Vec<Subscriber> {
+        let mut subscribers: Vec<Subscriber> =
+            self.subscribers.lock().unwrap().values().cloned().collect();
+        subscribers.sort_by(|a, b| a.email.cmp(&b.email));
+        subscribers
+    }
+
+    pub fn executed_batches(&self) -> Vec<Vec<EmailRequest>> {
+        self.batches.lock().unwrap().clone()
+    }
+
+    fn apply(&self, request: &EmailRequest) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        match request {
+            EmailRequest::UpsertSubscriber {
+                email,
+                fields,
+                groups,
+            } => {
+                let subscriber = subscribers
+                    .entry(email.clone())
+                    .or_insert_with(|| Subscriber {
+                        id: email.clone(),
+                        email: email.clone(),
+                        fields: BTreeMap::new(),
+                        groups: Vec::new(),
+                    });
+                subscriber.fields.extend(fields.clone());
+                for group in groups {
+                    if !subscriber.groups.contains(group) {
+                        subscriber.groups.push(group.clone());
+                    }
+                }
+            }
+            EmailRequest::AddToGroup {
+                subscriber_id,
+                group,
+            } => {
+                if let Some(subscriber) = subscribers.get_mut(subscriber_id) {
+                    if !subscriber.groups.contains(group) {
+                        subscriber.groups.push(group.clone());
+                    }
+                }
+            }
+            EmailRequest::RemoveFromGroup {
+                subscriber_id,
+                group,
+            } => {
+                if let Some(subscriber) = subscribers.get_mut(subscriber_id) {
+                    subscriber.groups.retain(|g| g != group);
+                }
+            }
+            EmailRequest::ForgetSubscriber { subscriber_id } => {
+                subscribers.remove(subscriber_id);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EmailProvider for InMemoryEmailProvider {
+    async fn upsert_subscriber(
+        &self,
+        email: &str,
+        fields: &BTreeMap<String, String>,
+        groups: &[String],
+    ) -> Result<(), EmailError> {
+        self.apply(&EmailRequest::UpsertSubscriber {
+            email: email.to_string(),
+            fields: fields.clone(),
+            groups: groups.to_vec(),
+        });
+        Ok(())
+    }
+
+    async fn add_to_group(&self, subscriber_id: &str, group: &str) -> Result<(), EmailError> {
+        self.apply(&EmailRequest::AddToGroup {
+            subscriber_id: subscriber_id.to_string(),
+            group: group.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn remove_from_group(&self, subscriber_id: &str, group: &str) -> Result<(), EmailError> {
+        self.apply(&EmailRequest::RemoveFromGroup {
+            subscriber_id: subscriber_id.to_string(),
+            group: group.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn find_subscriber(&self, email: &str) -> Result<Option<Subscriber>, EmailError> {
+        Ok(self.subscribers.lock().unwrap().get(email).cloned())
+    }
+
+    async fn forget_subscriber(&self, subscriber_id: &str) -> Result<(), EmailError> {
+        self.apply(&EmailRequest::ForgetSubscriber {
+            subscriber_id: subscriber_id.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn execute_batch(&self, requests: &[EmailRequest]) -> Result<BatchOutcome, EmailError> {
+        self.batches.lock().unwrap().push(requests.to_vec());
+        Ok(requests
+            .iter()
+            .map(|request| {
+                self.apply(request);
+                Ok(())
+            })
+            .collect())
+    }
+}
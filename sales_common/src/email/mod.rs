@@ -0,0 +1,111 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+pub mod mailerlite;
+pub mod memory;
+
+pub use mailerlite::{ApiVersion, MailerLite};
+pub use memory::InMemoryEmailProvider;
+
+// Provider implementation selected by the `provider` key of the `[email]` config section
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailProviderKind {
+    Mailerlite,
+    Memory,
+}
+
+pub_struct!(Clone, Debug, PartialEq, Serialize; Subscriber {
+    id: String,
+    email: String,
+    fields: BTreeMap<String, String>,
+    groups: Vec<String>,
+});
+
+// A single operation, either sent on its own or as part of a batch
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EmailRequest {
+    UpsertSubscriber {
+        email: String,
+        fields: BTreeMap<String, String>,
+        groups: Vec<String>,
+    },
+    AddToGroup {
+        subscriber_id: String,
+        group: String,
+    },
+    RemoveFromGroup {
+        subscriber_id: String,
+        group: String,
+    },
+    // Erases the subscriber and its data, it is never mailed again
+    ForgetSubscriber {
+        subscriber_id: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum EmailError {
+    Request(String),
+    Status { status: u16, body: String },
+    // Too many requests, with the number of seconds to wait when the provider tells it
+    RateLimited { retry_after: Option<u64> },
+    Parse(String),
+}
+
+impl fmt::Display for EmailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmailError::Request(err) => write!(f, "request failed: {}", err),
+            EmailError::Status { status, body } => {
+                write!(f, "received status {}. Response body: {}", status, body)
+            }
+            EmailError::RateLimited {
+                retry_after: Some(seconds),
+            } => write!(f, "rate limited, retry after {} seconds", seconds),
+            EmailError::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            EmailError::Parse(err) => write!(f, "unable to parse response: {}", err),
+        }
+    }
+}
+
+// Result of each request of a batch, in the order the requests were given
+pub type BatchOutcome = Vec<Result<(), EmailError>>;
+
+#[async_trait]
+pub trait EmailProvider: Send + Sync {
+    async fn upsert_subscriber(
+        &self,
+        email: &str,
+        fields: &BTreeMap<String, String>,
+        groups: &[String],
+    ) -> Result<(), EmailError>;
+
+    async fn add_to_group(&self, subscriber_id: &str, group: &str) -> Result<(), EmailError>;
+
+    async fn remove_from_group(&self, subscriber_id: &str, group: &str) -> Result<(), EmailError>;
+
+    async fn find_subscriber(&self, email: &str) -> Result<Option<Subscriber>, EmailError>;
+
+    async fn forget_subscriber(&self, subscriber_id: &str) -> Result<(), EmailError>;
+
+    // Fails as a whole only when the batch itself could not be executed, failures of
+    // individual requests are reported in the outcome
+    async fn execute_batch(&self, requests: &[EmailRequest]) -> Result<BatchOutcome, EmailError>;
+}
+
+pub fn new_provider(
+    kind: &EmailProviderKind,
+    api_version: ApiVersion,
+    base_url: &str,
+    api_key: &str,
+) -> Arc<dyn EmailProvider> {
+    match kind {
+        EmailProviderKind::Mailerlite => Arc::new(MailerLite::new(api_version, base_url, api_key)),
+        EmailProviderKind::Memory => Arc::new(InMemoryEmailProvider::default()),
+    }
+}
@@ -0,0 +1,8 @@
+#[macro_use]
+pub mod utils;
+pub mod config;
+pub mod crypto;
+pub mod email;
+pub mod logger;
+pub mod metadata_hash;
+pub mod repository;
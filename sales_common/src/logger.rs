@@ -37,7 +37,8 @@ struct LogPayload<'a> {
 
 impl Logger {
     pub fn new(config: &Watchtower) -> Self {
-        env_logger::init();
+        // Both binaries and their tests can create several loggers
+        let _ = env_logger::try_init();
         Logger {
             enabled: config.enabled,
             config: Arc::new(config.clone()),
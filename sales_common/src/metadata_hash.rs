@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+// The meta_hash emitted with a sale commits to the metadata sent to /add_metadata. Each scheme
+// is identified by its version, stored along the metadata, so records keep being checked with
+// the scheme they were created with. Never change a released scheme, register a new version.
+
+pub trait HashScheme: Sync {
+    fn hash(&self, email: &str, tax_state: &str, salt: &str) -> String;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u32", into = "u32")]
+pub enum HashVersion {
+    // sha256 of "email|tax_state|salt", truncated to 248 bits
+    #[default]
+    V1,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UnknownHashVersion(pub u32);
+
+impl fmt::Display for UnknownHashVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown hash version {}", self.0)
+    }
+}
+
+impl TryFrom<u32> for HashVersion {
+    type Error = UnknownHashVersion;
+
+    fn try_from(version: u32) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(HashVersion::V1),
+            _ => Err(UnknownHashVersion(version)),
+        }
+    }
+}
+
+impl From<HashVersion> for u32 {
+    fn from(version: HashVersion) -> Self {
+        match version {
+            HashVersion::V1 => 1,
+        }
+    }
+}
+
+impl HashVersion {
+    pub fn scheme(&self) -> &'static dyn HashScheme {
+        match self {
+            HashVersion::V1 => &Sha256Truncated,
+        }
+    }
+
+    pub fn hash(&self, email: &str, tax_state: &str, salt: &str) -> String {
+        self.scheme().hash(email, tax_state, salt)
+    }
+}
+
+struct Sha256Truncated;
+
+impl HashScheme for Sha256Truncated {
+    fn hash(&self, email: &str, tax_state: &str, salt: &str) -> String {
+        let separator = "|";
+        let data = format!(
+            "{}{}{}{}{}",
+            email,
+            separator,
+            tax_state.replace('|', ""),
+            separator,
+            salt
+        );
+
+        let mut hasher = Sha256::new();
+        hasher.update(data.as_bytes());
+        let hash_hex = hex::encode(hasher.finalize());
+
+        // Truncate the last two characters (8 bits) to make it a 248-bit hash so it fits in a felt
+        hash_hex[0..hash_hex.len() - 2].to_string()
+    }
+}
+
+#[cfg(test)]
+mod metadata_hash_tests {
+    use super::{HashVersion, UnknownHashVersion};
+
+    // (email, tax_state, salt, meta_hash), shared with the frontend and the contract
+    const V1_VECTORS: [(&str, &str, &str, &str); 3] = [
+        (
+            "john@example.com",
+            "FR",
+            "0x0123456789abcdef0123456789abcdef",
+            "c560663c0b22756909ddacf1fb6d742e910ccc93ce762e80240e3b661a28b4",
+        ),
+        (
+            "jane@example.com",
+            "US|CA",
+            "0x1",
+            "3af086980e1f3144dba29a65d43778eb8fba92afe82a35807c930d99135d0e",
+        ),
+        (
+            "",
+            "none",
+            "",
+            "a00f8422845f2761cb115c2e2ae7796cb3be78a24766cbb03cb3382786f716",
+        ),
+    ];
+
+    #[test]
+    fn test_v1_vectors() {
+        for (email, tax_state, salt, meta_hash) in V1_VECTORS {
+            assert_eq!(HashVersion::V1.hash(email, tax_state, salt), meta_hash);
+        }
+    }
+
+    #[test]
+    fn test_versions_are_numbers() {
+        assert_eq!(HashVersion::try_from(1), Ok(HashVersion::V1));
+        assert_eq!(HashVersion::try_from(0), Err(UnknownHashVersion(0)));
+        assert_eq!(u32::from(HashVersion::default()), 1);
+        assert_eq!(serde_json::to_string(&HashVersion::V1).unwrap(), "1");
+    }
+}
@@ -0,0 +1,682 @@
+use super::{
+    is_duplicate_key, Ledger, NotificationRequest, NotificationState, Repository, RepositoryError,
+    AUTO_RENEW_UPDATES, EMAIL_GROUPS, METADATA, NEWSLETTER, OUTBOX, SALES,
+};
+use crate::{crypto::CryptoError, email::EmailRequest, logger::Logger};
+use futures::future::BoxFuture;
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, from_document, to_bson, Bson, DateTime, Document},
+    options::IndexOptions,
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Schema changes are applied once and in order by whichever binary starts first, the applied
+// versions are recorded in `_migrations`. A migration can be interrupted or run concurrently by
+// both binaries, so it must be idempotent. Never edit a released migration, add a new one.
+
+pub const MIGRATIONS_COLLECTION: &str = "_migrations";
+// Documents a migration removed, kept along with the migration and collection they came from
+pub const QUARANTINE_COLLECTION: &str = "_quarantine";
+
+type MigrationFn = for<'a> fn(&'a Repository) -> BoxFuture<'a, Result<(), RepositoryError>>;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    apply: MigrationFn,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "processed ledgers keyed on tx_hash and domain",
+        apply: processed_ledgers_key,
+    },
+    Migration {
+        version: 2,
+        name: "outbox indexes",
+        apply: outbox_indexes,
+    },
+    Migration {
+        version: 3,
+        name: "unique metadata, newsletter and email_groups",
+        apply: unique_api_documents,
+    },
+    Migration {
+        version: 4,
+        name: "sales and auto_renew_updates lookup indexes",
+        apply: lookup_indexes,
+    },
+    Migration {
+        version: 5,
+        name: "metadata verification index",
+        apply: metadata_verification_index,
+    },
+    Migration {
+        version: 6,
+        name: "metadata hash_version backfill",
+        apply: metadata_hash_version,
+    },
+    Migration {
+        version: 7,
+        name: "encrypted personal data",
+        apply: encrypt_personal_data,
+    },
+    Migration {
+        version: 8,
+        name: "newsletter confirmation index",
+        apply: newsletter_confirmation_index,
+    },
+    Migration {
+        version: 9,
+        name: "email_groups verification index",
+        apply: email_groups_verification_index,
+    },
+    Migration {
+        version: 10,
+        name: "sales domain index",
+        apply: sales_domain_index,
+    },
+    Migration {
+        version: 11,
+        name: "drop failed_notifications",
+        apply: drop_failed_notifications,
+    },
+    Migration {
+        version: 12,
+        name: "encrypted outbox requests",
+        apply: encrypt_outbox_requests,
+    },
+    Migration {
+        version: 13,
+        name: "outbox email order",
+        apply: outbox_email_order,
+    },
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MigrationDoc {
+    version: i32,
+    name: String,
+    applied_at: DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct QuarantineDoc {
+    migration: i32,
+    collection: String,
+    document: Document,
+    quarantined_at: DateTime,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Read(mongodb::error::Error),
+    Apply {
+        version: i32,
+        name: &'static str,
+        source: RepositoryError,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::Read(err) => write!(f, "unable to read applied migrations: {}", err),
+            MigrationError::Apply {
+                version,
+                name,
+                source,
+            } => write!(f, "migration {} ({}) failed: {}", version, name, source),
+        }
+    }
+}
+
+// Applies the migrations that were not applied yet and returns them
+pub async fn run(repo: &Repository) -> Result<Vec<&'static Migration>, MigrationError> {
+    let collection: Collection<MigrationDoc> = repo.db().collection(MIGRATIONS_COLLECTION);
+    create_index(
+        repo.db(),
+        MIGRATIONS_COLLECTION,
+        doc! { "version": 1 },
+        true,
+    )
+    .await
+    .map_err(MigrationError::Read)?;
+    let applied: Vec<i32> = collection
+        .find(None, None)
+        .await
+        .map_err(MigrationError::Read)?
+        .map_ok(|migration| migration.version)
+        .try_collect()
+        .await
+        .map_err(MigrationError::Read)?;
+
+    let mut newly_applied = Vec::new();
+    for migration in MIGRATIONS {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+        let error = |source| MigrationError::Apply {
+            version: migration.version,
+            name: migration.name,
+            source,
+        };
+        (migration.apply)(repo).await.map_err(error)?;
+        let record = MigrationDoc {
+            version: migration.version,
+            name: migration.name.to_string(),
+            applied_at: DateTime::now(),
+        };
+        match collection.insert_one(record, None).await {
+            Ok(_) => newly_applied.push(migration),
+            // the other binary recorded it first
+            Err(e) if is_duplicate_key(&e) => (),
+            Err(e) => return Err(error(e.into())),
+        }
+    }
+    Ok(newly_applied)
+}
+
+// Runs the migrations at startup, logging what was applied and every document they quarantined.
+// Returns false if the database could not be migrated and the binary should stop.
+pub async fn apply(repo: &Repository, logger: &Logger) -> bool {
+    match run(repo).await {
+        Ok(applied) => {
+            for migration in applied {
+                logger.info(format!(
+                    "database: applied migration {} ({})",
+                    migration.version, migration.name
+                ));
+                log_quarantined(repo, logger, migration.version).await;
+            }
+            true
+        }
+        Err(e) => {
+            logger.severe(format!("unable to migrate the database: {}", e));
+            false
+        }
+    }
+}
+
+async fn log_quarantined(repo: &Repository, logger: &Logger, version: i32) {
+    let quarantined: mongodb::error::Result<Vec<QuarantineDoc>> = match repo
+        .db()
+        .collection::<QuarantineDoc>(QUARANTINE_COLLECTION)
+        .find(doc! { "migration": version }, None)
+        .await
+    {
+        Ok(cursor) => cursor.try_collect().await,
+        Err(e) => Err(e),
+    };
+    match quarantined {
+        Ok(quarantined) => {
+            for quarantined in quarantined {
+                logger.warning(format!(
+                    "database: migration {} moved the {} document {} to {}",
+                    version,
+                    quarantined.collection,
+                    quarantined.document.get("_id").unwrap_or(&Bson::Null),
+                    QUARANTINE_COLLECTION
+                ));
+            }
+        }
+        Err(e) => logger.severe(format!(
+            "unable to read the documents quarantined by migration {}: {}",
+            version, e
+        )),
+    }
+}
+
+async fn create_index(
+    db: &Database,
+    collection: &str,
+    keys: Document,
+    unique: bool,
+) -> mongodb::error::Result<()> {
+    db.collection::<Document>(collection)
+        .create_index(
+            IndexModel::builder()
+                .keys(keys)
+                .options(IndexOptions::builder().unique(unique).build())
+                .build(),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+// Moves the documents sharing their `keys` with an older one to the quarantine collection, so a
+// unique index can be built. `apply` logs each of them.
+async fn quarantine_duplicates(
+    db: &Database,
+    version: i32,
+    collection_name: &str,
+    keys: &[&str],
+) -> mongodb::error::Result<()> {
+    create_index(
+        db,
+        QUARANTINE_COLLECTION,
+        doc! { "collection": 1, "document._id": 1 },
+        true,
+    )
+    .await?;
+    let collection = db.collection::<Document>(collection_name);
+    let quarantine = db.collection::<QuarantineDoc>(QUARANTINE_COLLECTION);
+    let group_id: Document = keys
+        .iter()
+        .map(|key| (key.to_string(), Bson::String(format!("${}", key))))
+        .collect();
+    let mut duplicates = collection
+        .aggregate(
+            [
+                doc! { "$sort": { "_id": 1 } },
+                doc! { "$group": { "_id": group_id, "ids": { "$push": "$_id" } } },
+                doc! { "$match": { "ids.1": { "$exists": true } } },
+            ],
+            None,
+        )
+        .await?;
+    while let Some(duplicate) = duplicates.try_next().await? {
+        let ids = duplicate.get_array("ids").cloned().unwrap_or_default();
+        let mut documents = collection
+            .find(doc! { "_id": { "$in": &ids[1..] } }, None)
+            .await?;
+        while let Some(document) = documents.try_next().await? {
+            let id = document.get("_id").cloned().unwrap_or(Bson::Null);
+            let quarantined = QuarantineDoc {
+                migration: version,
+                collection: collection_name.to_string(),
+                document,
+                quarantined_at: DateTime::now(),
+            };
+            match quarantine.insert_one(quarantined, None).await {
+                // quarantined by an interrupted run
+                Err(e) if !is_duplicate_key(&e) => return Err(e),
+                _ => (),
+            }
+            collection.delete_one(doc! { "_id": id }, None).await?;
+        }
+    }
+    Ok(())
+}
+
+fn processed_ledgers_key(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        let db = repo.db();
+        for ledger in [Ledger::Purchases, Ledger::Renewals] {
+            // Markers written before the key was fixed had no domain and never matched anything,
+            // they would collide in the unique index
+            db.collection::<Document>(ledger.collection_name())
+                .delete_many(doc! { "domain": { "$exists": false } }, None)
+                .await?;
+            create_index(
+                db,
+                ledger.collection_name(),
+                doc! { "tx_hash": 1, "domain": 1 },
+                true,
+            )
+            .await?;
+        }
+        Ok(())
+    })
+}
+
+fn outbox_indexes(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        let db = repo.db();
+        create_index(
+            db,
+            OUTBOX,
+            doc! { "kind": 1, "tx_hash": 1, "domain": 1 },
+            true,
+        )
+        .await?;
+        create_index(db, OUTBOX, doc! { "state": 1, "next_attempt_at": 1 }, false).await?;
+        Ok(())
+    })
+}
+
+// Newsletter emails are unique through their keyed hash, see `encrypt_personal_data`
+fn unique_api_documents(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        let db = repo.db();
+        quarantine_duplicates(db, 3, METADATA, &["meta_hash"]).await?;
+        create_index(db, METADATA, doc! { "meta_hash": 1 }, true).await?;
+        quarantine_duplicates(db, 3, EMAIL_GROUPS, &["tx_hash", "group"]).await?;
+        create_index(db, EMAIL_GROUPS, doc! { "tx_hash": 1, "group": 1 }, true).await?;
+        Ok(())
+    })
+}
+
+fn lookup_indexes(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        let db = repo.db();
+        create_index(db, SALES, doc! { "tx_hash": 1, "domain": 1 }, false).await?;
+        create_index(db, SALES, doc! { "meta_hash": 1 }, false).await?;
+        create_index(
+            db,
+            AUTO_RENEW_UPDATES,
+            doc! { "tx_hash": 1, "domain": 1 },
+            false,
+        )
+        .await?;
+        create_index(db, AUTO_RENEW_UPDATES, doc! { "meta_hash": 1 }, false).await?;
+        Ok(())
+    })
+}
+
+fn metadata_verification_index(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        let db = repo.db();
+        create_index(
+            db,
+            METADATA,
+            doc! { "unverified": 1, "verify_until": 1 },
+            false,
+        )
+        .await?;
+        Ok(())
+    })
+}
+
+// Metadata stored before the hash scheme was versioned was hashed with v1
+fn metadata_hash_version(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        repo.db()
+            .collection::<Document>(METADATA)
+            .update_many(
+                doc! { "hash_version": { "$exists": false } },
+                doc! { "$set": { "hash_version": 1 } },
+                None,
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+// Encrypts the personal data stored in plaintext. Newsletter emails are unique through their
+// keyed hash, as two ciphertexts of the same email differ. Fields encrypted later have their own
+// migration.
+fn encrypt_personal_data(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        repo.reencrypt_fields(METADATA, &["email", "tax_state"])
+            .await?;
+        repo.reencrypt_fields(NEWSLETTER, &["email", "address"])
+            .await?;
+        let db = repo.db();
+        quarantine_duplicates(db, 7, NEWSLETTER, &["email_hash"]).await?;
+        create_index(db, NEWSLETTER, doc! { "email_hash": 1 }, true).await?;
+        create_index(db, METADATA, doc! { "email_hash": 1 }, false).await?;
+        Ok(())
+    })
+}
+
+fn newsletter_confirmation_index(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        create_index(
+            repo.db(),
+            NEWSLETTER,
+            doc! { "status": 1, "confirm_until": 1 },
+            false,
+        )
+        .await?;
+        Ok(())
+    })
+}
+
+fn email_groups_verification_index(
+    repo: &Repository,
+) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        create_index(
+            repo.db(),
+            EMAIL_GROUPS,
+            doc! { "unverified": 1, "verify_until": 1 },
+            false,
+        )
+        .await?;
+        Ok(())
+    })
+}
+
+// Sales are searched by domain from the admin API, the latest first
+fn sales_domain_index(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        create_index(
+            repo.db(),
+            SALES,
+            doc! { "domain": 1, "timestamp": -1 },
+            false,
+        )
+        .await?;
+        Ok(())
+    })
+}
+
+// Sales whose notification failed were recorded there before the outbox, without their request.
+// They were not marked as processed, so they already went through the outbox since.
+fn drop_failed_notifications(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        repo.db()
+            .collection::<Document>("failed_notifications")
+            .drop(None)
+            .await?;
+        Ok(())
+    })
+}
+
+// Requests were stored as plaintext documents. They are encrypted, unless the notification was
+// sent, in which case the request is not kept anymore.
+fn encrypt_outbox_requests(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        let outbox = repo.db().collection::<Document>(OUTBOX);
+        let sent = to_bson(&NotificationState::Sent).map_err(mongodb::error::Error::from)?;
+        outbox
+            .update_many(
+                doc! { "state": sent },
+                doc! { "$set": { "request": null } },
+                None,
+            )
+            .await?;
+        let mut plaintext = outbox
+            .find(doc! { "request": { "$type": "object" } }, None)
+            .await?;
+        while let Some(notification) = plaintext.try_next().await? {
+            let request: EmailRequest = from_document(
+                notification
+                    .get_document("request")
+                    .cloned()
+                    .unwrap_or_default(),
+            )
+            .map_err(mongodb::error::Error::from)?;
+            outbox
+                .update_one(
+                    doc! { "_id": notification.get("_id") },
+                    doc! {
+                        "$set": {
+                            "request": repo.encrypt_request(&NotificationRequest::Email(request))?
+                        }
+                    },
+                    None,
+                )
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+// Notifications of an email are delivered in order, through the keyed hash of the email of their
+// request. It is filled in for the ones still to be sent.
+fn outbox_email_order(repo: &Repository) -> BoxFuture<'_, Result<(), RepositoryError>> {
+    Box::pin(async move {
+        let db = repo.db();
+        let outbox = db.collection::<Document>(OUTBOX);
+        let mut unsent = outbox
+            .find(
+                doc! { "request": { "$type": "string" }, "email_hash": { "$exists": false } },
+                None,
+            )
+            .await?;
+        while let Some(notification) = unsent.try_next().await? {
+            let json = repo.cipher().decrypt(
+                "request",
+                notification.get_str("request").unwrap_or_default(),
+            )?;
+            let request: NotificationRequest =
+                serde_json::from_str(&json).map_err(|_| CryptoError::Malformed)?;
+            let email_hash = request
+                .email()
+                .map(|email| repo.cipher().blind_index(email));
+            outbox
+                .update_one(
+                    doc! { "_id": notification.get("_id") },
+                    doc! { "$set": { "email_hash": email_hash } },
+                    None,
+                )
+                .await?;
+        }
+        create_index(db, OUTBOX, doc! { "email_hash": 1, "created_at": 1 }, false).await?;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod migrations_tests {
+    use super::{run, MIGRATIONS, MIGRATIONS_COLLECTION, QUARANTINE_COLLECTION};
+    use crate::repository::{
+        test_utils::{cipher, test_db},
+        Repository, EMAIL_GROUPS, METADATA, NEWSLETTER, OUTBOX,
+    };
+    use mongodb::bson::{doc, Bson, Document};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_versions_are_sequential() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i32 + 1);
+        }
+        let names: HashSet<&str> = MIGRATIONS.iter().map(|migration| migration.name).collect();
+        assert_eq!(names.len(), MIGRATIONS.len());
+    }
+
+    #[tokio::test]
+    async fn test_migrations_dedupe_and_run_once() {
+        let Some(db) = test_db("migrations").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        let email_groups = db.collection::<Document>(EMAIL_GROUPS);
+        let group = doc! { "tx_hash": "0x1", "group": "1" };
+        email_groups
+            .insert_many([group.clone(), group.clone()], None)
+            .await
+            .unwrap();
+        let newsletter = doc! { "email": "john@example.com", "source": "newsletter_subscription" };
+        db.collection::<Document>(NEWSLETTER)
+            .insert_many([newsletter.clone(), newsletter], None)
+            .await
+            .unwrap();
+        let outbox = db.collection::<Document>(OUTBOX);
+        let notification = |domain: &str, state: &str| {
+            doc! {
+                "kind": "purchase",
+                "tx_hash": "0x1",
+                "domain": domain,
+                "request": { "type": "add_to_group", "subscriber_id": "42", "group": "1" },
+                "state": state,
+            }
+        };
+        outbox
+            .insert_many(
+                [
+                    notification("john.stark", "failed"),
+                    notification("jane.stark", "sent"),
+                    doc! {
+                        "kind": "renewal",
+                        "tx_hash": "0x1",
+                        "domain": "john.stark",
+                        "request": {
+                            "type": "upsert_subscriber",
+                            "email": "john@example.com",
+                            "fields": {},
+                            "groups": ["ar_group"],
+                        },
+                        "state": "pending",
+                    },
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+        let failed_notifications = db.collection::<Document>("failed_notifications");
+        failed_notifications
+            .insert_one(doc! { "tx_hash": "0x1", "domain": "john.stark" }, None)
+            .await
+            .unwrap();
+
+        assert_eq!(run(&repo).await.unwrap().len(), MIGRATIONS.len());
+        assert!(run(&repo).await.unwrap().is_empty());
+
+        assert_eq!(email_groups.count_documents(None, None).await.unwrap(), 1);
+        assert!(email_groups.insert_one(group, None).await.is_err());
+        assert_eq!(
+            db.collection::<Document>(NEWSLETTER)
+                .count_documents(None, None)
+                .await
+                .unwrap(),
+            1
+        );
+        // the duplicates are kept aside rather than deleted
+        let quarantine = db.collection::<Document>(QUARANTINE_COLLECTION);
+        for (migration, collection) in [(3, EMAIL_GROUPS), (7, NEWSLETTER)] {
+            let filter = doc! { "migration": migration, "collection": collection };
+            assert_eq!(quarantine.count_documents(filter, None).await.unwrap(), 1);
+        }
+        let failed = outbox
+            .find_one(doc! { "kind": "purchase", "domain": "john.stark" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(failed.get_str("request").unwrap().starts_with("enc:"));
+        assert_eq!(failed.get("email_hash"), Some(&Bson::Null));
+        let renewal = outbox
+            .find_one(doc! { "kind": "renewal" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            renewal.get_str("email_hash").unwrap(),
+            repo.cipher().blind_index("john@example.com")
+        );
+        let sent = outbox
+            .find_one(doc! { "domain": "jane.stark" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent.get("request"), Some(&Bson::Null));
+        let collections = db.list_collection_names(None).await.unwrap();
+        assert!(!collections.contains(&"failed_notifications".to_string()));
+        let metadata = doc! { "meta_hash": "abc", "email": "john@example.com" };
+        let metadata_collection = db.collection::<Document>(METADATA);
+        metadata_collection
+            .insert_one(metadata.clone(), None)
+            .await
+            .unwrap();
+        assert!(metadata_collection
+            .insert_one(metadata, None)
+            .await
+            .is_err());
+        assert_eq!(
+            db.collection::<Document>(MIGRATIONS_COLLECTION)
+                .count_documents(None, None)
+                .await
+                .unwrap(),
+            MIGRATIONS.len() as u64
+        );
+        db.drop(None).await.unwrap();
+    }
+}
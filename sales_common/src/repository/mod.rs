@@ -0,0 +1,1767 @@
+use crate::crypto::{Cipher, CryptoError};
+use futures::stream::TryStreamExt;
+use mongodb::{
+    bson::{doc, to_bson, Bson, DateTime, Document},
+    error::{BulkWriteFailure, ErrorKind, WriteFailure},
+    options::{FindOneOptions, FindOptions, InsertManyOptions, UpdateOptions},
+    Collection, Cursor, Database,
+};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+pub mod migrations;
+pub mod models;
+pub mod pipeline;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+
+pub use models::{
+    DataRequestAction, DataRequestDoc, EmailGroupDoc, ErasedData, IndexedRenewalDoc,
+    IndexedSaleDoc, MetadataDoc, NewsletterDoc, NewsletterStatus, NotificationDoc,
+    NotificationKind, NotificationRequest, NotificationState, PersonalData, ProcessedDoc,
+    ReenewalToggledDoc, SaleDoc,
+};
+pub use pipeline::Pipeline;
+
+pub const SALES: &str = "sales";
+pub const AUTO_RENEW_UPDATES: &str = "auto_renew_updates";
+pub const METADATA: &str = "metadata";
+pub const EMAIL_GROUPS: &str = "email_groups";
+pub const NEWSLETTER: &str = "newsletter";
+pub const OUTBOX: &str = "outbox";
+pub const DATA_REQUESTS: &str = "data_requests";
+
+// Fields of the documents returned by the aggregations, they must match the structs
+const METADATA_FIELDS: [&str; 4] = ["meta_hash", "email", "tax_state", "salt"];
+const SALE_FIELDS: [&str; 8] = [
+    "tx_hash",
+    "domain",
+    "price",
+    "payer",
+    "timestamp",
+    "expiry",
+    "metadata",
+    "same_tx_groups",
+];
+const RENEWAL_FIELDS: [&str; 6] = [
+    "tx_hash",
+    "domain",
+    "renewer",
+    "allowance",
+    "metadata",
+    "same_tx_groups",
+];
+
+// Personal data encrypted at rest, emails also get a keyed hash to be looked up
+const ENCRYPTED_FIELDS: [(&str, &[&str]); 3] = [
+    (METADATA, &["email", "tax_state"]),
+    (NEWSLETTER, &["email", "address"]),
+    (OUTBOX, &["request"]),
+];
+
+// Ledgers of what sale_actions already handled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ledger {
+    Purchases,
+    Renewals,
+}
+
+impl Ledger {
+    pub fn collection_name(&self) -> &'static str {
+        match self {
+            Ledger::Purchases => "processed",
+            Ledger::Renewals => "ar_processed",
+        }
+    }
+}
+
+// A sale or renewal is identified by its transaction and domain, as one transaction can touch
+// several domains. This is the key of the processed ledgers and of the outbox.
+pub fn processed_key(tx_hash: &str, domain: &str) -> Document {
+    doc! { "tx_hash": tx_hash, "domain": domain }
+}
+
+// A sale can have both a purchase and a renewal notification
+fn notification_key(
+    kind: NotificationKind,
+    tx_hash: &str,
+    domain: &str,
+) -> mongodb::error::Result<Document> {
+    let mut key = processed_key(tx_hash, domain);
+    key.insert("kind", to_bson(&kind)?);
+    Ok(key)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataUpsert {
+    Inserted,
+    // the same metadata was already stored
+    Replayed,
+    // different metadata is stored with this meta_hash
+    Conflict,
+}
+
+// Groups of a transaction, split between the ones just stored and the ones already there
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EmailGroupsInsert {
+    pub added: Vec<String>,
+    pub existing: Vec<String>,
+}
+
+// Whether a write was rejected by a unique index
+pub fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    Database(mongodb::error::Error),
+    Crypto(CryptoError),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Database(err) => write!(f, "{}", err),
+            RepositoryError::Crypto(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for RepositoryError {
+    fn from(err: mongodb::error::Error) -> Self {
+        RepositoryError::Database(err)
+    }
+}
+
+impl From<CryptoError> for RepositoryError {
+    fn from(err: CryptoError) -> Self {
+        RepositoryError::Crypto(err)
+    }
+}
+
+// Typed access to the collections shared by the API and sale_actions. Personal data goes through
+// the cipher: documents are written encrypted and read back with `decrypt_*`.
+#[derive(Clone, Debug)]
+pub struct Repository {
+    db: Database,
+    cipher: Arc<Cipher>,
+}
+
+impl Repository {
+    pub fn new(db: Database, cipher: Cipher) -> Self {
+        Repository {
+            db,
+            cipher: Arc::new(cipher),
+        }
+    }
+
+    pub fn db(&self) -> &Database {
+        &self.db
+    }
+
+    pub fn cipher(&self) -> &Cipher {
+        &self.cipher
+    }
+
+    pub async fn ping(&self) -> mongodb::error::Result<()> {
+        self.db.run_command(doc! {"ping": 1}, None).await?;
+        Ok(())
+    }
+
+    pub fn sales(&self) -> Collection<IndexedSaleDoc> {
+        self.db.collection(SALES)
+    }
+
+    pub fn auto_renew_updates(&self) -> Collection<IndexedRenewalDoc> {
+        self.db.collection(AUTO_RENEW_UPDATES)
+    }
+
+    pub fn metadata(&self) -> Collection<MetadataDoc> {
+        self.db.collection(METADATA)
+    }
+
+    pub fn email_groups(&self) -> Collection<EmailGroupDoc> {
+        self.db.collection(EMAIL_GROUPS)
+    }
+
+    pub fn newsletter(&self) -> Collection<NewsletterDoc> {
+        self.db.collection(NEWSLETTER)
+    }
+
+    pub fn outbox(&self) -> Collection<NotificationDoc> {
+        self.db.collection(OUTBOX)
+    }
+
+    pub fn processed(&self, ledger: Ledger) -> Collection<ProcessedDoc> {
+        self.db.collection(ledger.collection_name())
+    }
+
+    pub fn encrypt_metadata(&self, metadata: &MetadataDoc) -> Result<MetadataDoc, CryptoError> {
+        Ok(MetadataDoc {
+            email: self.cipher.encrypt("email", &metadata.email)?,
+            tax_state: self.cipher.encrypt("tax_state", &metadata.tax_state)?,
+            email_hash: self.cipher.blind_index(&metadata.email),
+            ..metadata.clone()
+        })
+    }
+
+    pub fn decrypt_metadata(&self, metadata: &MetadataDoc) -> Result<MetadataDoc, CryptoError> {
+        Ok(MetadataDoc {
+            email: self.cipher.decrypt("email", &metadata.email)?,
+            tax_state: self.cipher.decrypt("tax_state", &metadata.tax_state)?,
+            ..metadata.clone()
+        })
+    }
+
+    pub fn decrypt_newsletter(
+        &self,
+        newsletter: &NewsletterDoc,
+    ) -> Result<NewsletterDoc, CryptoError> {
+        Ok(NewsletterDoc {
+            email: self.cipher.decrypt("email", &newsletter.email)?,
+            address: match &newsletter.address {
+                Some(address) => Some(self.cipher.decrypt("address", address)?),
+                None => None,
+            },
+            ..newsletter.clone()
+        })
+    }
+
+    // Notification requests hold the email and the fields sent to the email provider, they are
+    // stored as encrypted JSON
+    pub fn encrypt_request(&self, request: &NotificationRequest) -> Result<String, CryptoError> {
+        let json = serde_json::to_string(request).map_err(|_| CryptoError::Encryption)?;
+        self.cipher.encrypt("request", &json)
+    }
+
+    // Fails for notifications whose request was cleared once they were sent
+    pub fn decrypt_request(
+        &self,
+        notification: &NotificationDoc,
+    ) -> Result<NotificationRequest, CryptoError> {
+        let request = notification
+            .request
+            .as_deref()
+            .ok_or(CryptoError::Malformed)?;
+        serde_json::from_str(&self.cipher.decrypt("request", request)?)
+            .map_err(|_| CryptoError::Malformed)
+    }
+
+    // Metadata is keyed on its meta_hash, submitting it again is only accepted if it is identical
+    pub async fn upsert_metadata(
+        &self,
+        metadata: &MetadataDoc,
+    ) -> Result<MetadataUpsert, RepositoryError> {
+        let encrypted = self.encrypt_metadata(metadata)?;
+        loop {
+            match self.metadata().insert_one(&encrypted, None).await {
+                Ok(_) => return Ok(MetadataUpsert::Inserted),
+                Err(e) if !is_duplicate_key(&e) => return Err(e.into()),
+                Err(_) => (),
+            }
+            let filter = doc! { "meta_hash": &metadata.meta_hash };
+            if let Some(existing) = self.metadata().find_one(filter, None).await? {
+                return Ok(
+                    if self.decrypt_metadata(&existing)?.same_content(metadata) {
+                        MetadataUpsert::Replayed
+                    } else {
+                        MetadataUpsert::Conflict
+                    },
+                );
+            }
+            // deleted since the insert failed, try again
+        }
+    }
+
+    // Stores the groups in a single unordered insert, subscribing twice to the same group is a
+    // no-op. Only duplicates are tolerated, retrying after a failure stores what is missing.
+    pub async fn insert_email_groups(
+        &self,
+        email_groups: &[EmailGroupDoc],
+    ) -> mongodb::error::Result<EmailGroupsInsert> {
+        if email_groups.is_empty() {
+            return Ok(EmailGroupsInsert::default());
+        }
+        let options = InsertManyOptions::builder().ordered(false).build();
+        let duplicates: BTreeSet<usize> =
+            match self.email_groups().insert_many(email_groups, options).await {
+                Ok(_) => BTreeSet::new(),
+                Err(e) => match e.kind.as_ref() {
+                    ErrorKind::BulkWrite(BulkWriteFailure {
+                        write_errors: Some(errors),
+                        write_concern_error: None,
+                        ..
+                    }) if errors.iter().all(|error| error.code == 11000) => {
+                        errors.iter().map(|error| error.index).collect()
+                    }
+                    _ => return Err(e),
+                },
+            };
+        let mut insert = EmailGroupsInsert::default();
+        for (index, email_group) in email_groups.iter().enumerate() {
+            let groups = if duplicates.contains(&index) {
+                &mut insert.existing
+            } else {
+                &mut insert.added
+            };
+            groups.push(email_group.group.clone());
+        }
+        Ok(insert)
+    }
+
+    // Looked up through the keyed hash of the email, returned decrypted
+    pub async fn find_newsletter(
+        &self,
+        email: &str,
+    ) -> Result<Option<NewsletterDoc>, RepositoryError> {
+        self.find_newsletter_by_hash(&self.cipher.blind_index(email))
+            .await
+    }
+
+    pub async fn find_newsletter_by_hash(
+        &self,
+        email_hash: &str,
+    ) -> Result<Option<NewsletterDoc>, RepositoryError> {
+        let filter = doc! { "email_hash": email_hash };
+        match self.newsletter().find_one(filter, None).await? {
+            Some(newsletter) => Ok(Some(self.decrypt_newsletter(&newsletter)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn confirm_newsletter(&self, email_hash: &str) -> mongodb::error::Result<()> {
+        self.newsletter()
+            .update_one(
+                doc! { "email_hash": email_hash },
+                doc! {
+                    "$set": { "status": to_bson(&NewsletterStatus::Active)? },
+                    "$unset": { "confirm_until": "" },
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn expired_newsletter_filter() -> mongodb::error::Result<Document> {
+        Ok(doc! {
+            "status": to_bson(&NewsletterStatus::Pending)?,
+            "confirm_until": { "$lt": DateTime::now() },
+        })
+    }
+
+    // Pending subscriptions that were not confirmed in time, returned decrypted
+    pub async fn expired_newsletters(&self) -> Result<Vec<NewsletterDoc>, RepositoryError> {
+        let expired: Vec<NewsletterDoc> = self
+            .newsletter()
+            .find(Self::expired_newsletter_filter()?, None)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(expired
+            .iter()
+            .map(|newsletter| self.decrypt_newsletter(newsletter))
+            .collect::<Result<_, _>>()?)
+    }
+
+    // Deletes a subscription if it is still pending and expired, returns whether it was deleted
+    pub async fn purge_newsletter(&self, email_hash: &str) -> mongodb::error::Result<bool> {
+        let mut filter = Self::expired_newsletter_filter()?;
+        filter.insert("email_hash", email_hash);
+        let result = self.newsletter().delete_one(filter, None).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    // Returns whether a subscription was deleted
+    pub async fn delete_newsletter_by_hash(
+        &self,
+        email_hash: &str,
+    ) -> mongodb::error::Result<bool> {
+        let result = self
+            .newsletter()
+            .delete_one(doc! { "email_hash": email_hash }, None)
+            .await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    pub async fn insert_newsletter(
+        &self,
+        newsletter: &NewsletterDoc,
+    ) -> Result<(), RepositoryError> {
+        let encrypted = NewsletterDoc {
+            email: self.cipher.encrypt("email", &newsletter.email)?,
+            email_hash: self.cipher.blind_index(&newsletter.email),
+            address: match &newsletter.address {
+                Some(address) => Some(self.cipher.encrypt("address", address)?),
+                None => None,
+            },
+            ..newsletter.clone()
+        };
+        self.newsletter().insert_one(encrypted, None).await?;
+        Ok(())
+    }
+
+    // Transactions of the sales and renewals using this metadata
+    async fn metadata_tx_hashes(
+        &self,
+        metadata: &[MetadataDoc],
+    ) -> mongodb::error::Result<Vec<Bson>> {
+        let meta_hashes: Vec<&str> = metadata.iter().map(|m| m.meta_hash.as_str()).collect();
+        let filter = doc! { "meta_hash": { "$in": meta_hashes } };
+        let mut tx_hashes = Vec::new();
+        for collection in [SALES, AUTO_RENEW_UPDATES] {
+            for tx_hash in self
+                .db
+                .collection::<Document>(collection)
+                .distinct("tx_hash", filter.clone(), None)
+                .await?
+            {
+                if !tx_hashes.contains(&tx_hash) {
+                    tx_hashes.push(tx_hash);
+                }
+            }
+        }
+        Ok(tx_hashes)
+    }
+
+    async fn find_metadata_by_email(
+        &self,
+        email: &str,
+    ) -> mongodb::error::Result<Vec<MetadataDoc>> {
+        self.find_metadata_by_hash(&self.cipher.blind_index(email))
+            .await
+    }
+
+    async fn find_metadata_by_hash(
+        &self,
+        email_hash: &str,
+    ) -> mongodb::error::Result<Vec<MetadataDoc>> {
+        self.metadata()
+            .find(doc! { "email_hash": email_hash }, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    // Email whose keyed hash is `email_hash`, from its newsletter subscription or its metadata
+    pub async fn find_email(&self, email_hash: &str) -> Result<Option<String>, RepositoryError> {
+        if let Some(newsletter) = self.find_newsletter_by_hash(email_hash).await? {
+            return Ok(Some(newsletter.email));
+        }
+        match self.find_metadata_by_hash(email_hash).await?.first() {
+            Some(metadata) => Ok(Some(self.decrypt_metadata(metadata)?.email)),
+            None => Ok(None),
+        }
+    }
+
+    // Wallets which bought or renewed with the metadata of an email, as indexed
+    pub async fn find_email_wallets(
+        &self,
+        email_hash: &str,
+    ) -> mongodb::error::Result<Vec<String>> {
+        let metadata = self.find_metadata_by_hash(email_hash).await?;
+        let meta_hashes: Vec<&str> = metadata.iter().map(|m| m.meta_hash.as_str()).collect();
+        let filter = doc! { "meta_hash": { "$in": meta_hashes } };
+        let mut wallets = Vec::new();
+        for (collection, field) in [(SALES, "payer"), (AUTO_RENEW_UPDATES, "renewer")] {
+            for wallet in self
+                .db
+                .collection::<Document>(collection)
+                .distinct(field, filter.clone(), None)
+                .await?
+            {
+                if let Bson::String(wallet) = wallet {
+                    wallets.push(wallet);
+                }
+            }
+        }
+        Ok(wallets)
+    }
+
+    // Groups chosen in the transactions of the metadata of an email
+    pub async fn find_email_groups(
+        &self,
+        email_hash: &str,
+    ) -> mongodb::error::Result<Vec<EmailGroupDoc>> {
+        let metadata = self.find_metadata_by_hash(email_hash).await?;
+        let in_tx_hashes = doc! { "tx_hash": { "$in": self.metadata_tx_hashes(&metadata).await? } };
+        self.email_groups()
+            .find(in_tx_hashes, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    // Removes `groups` from the transactions of the metadata of an email, so the sales left to
+    // process don't add the email to them again. Returns how many were removed.
+    pub async fn remove_email_groups(
+        &self,
+        email_hash: &str,
+        groups: &[String],
+    ) -> mongodb::error::Result<u64> {
+        let metadata = self.find_metadata_by_hash(email_hash).await?;
+        let filter = doc! {
+            "tx_hash": { "$in": self.metadata_tx_hashes(&metadata).await? },
+            "group": { "$in": groups },
+        };
+        Ok(self
+            .email_groups()
+            .delete_many(filter, None)
+            .await?
+            .deleted_count)
+    }
+
+    // Email groups and notifications are linked to the email through the transactions of its
+    // metadata. Indexed sales and renewals are public on-chain data and are not included.
+    pub async fn find_personal_data(&self, email: &str) -> Result<PersonalData, RepositoryError> {
+        let metadata = self.find_metadata_by_email(email).await?;
+        let in_tx_hashes = doc! { "tx_hash": { "$in": self.metadata_tx_hashes(&metadata).await? } };
+        Ok(PersonalData {
+            metadata: metadata
+                .iter()
+                .map(|metadata| self.decrypt_metadata(metadata))
+                .collect::<Result<_, _>>()?,
+            newsletter: self.find_newsletter(email).await?,
+            email_groups: self
+                .email_groups()
+                .find(in_tx_hashes.clone(), None)
+                .await?
+                .try_collect()
+                .await?,
+            notifications: self.find_notification_documents(in_tx_hashes).await?,
+        })
+    }
+
+    // Outbox entries as stored, with their request decrypted
+    async fn find_notification_documents(
+        &self,
+        filter: Document,
+    ) -> Result<Vec<Document>, RepositoryError> {
+        let mut notifications: Vec<Document> = self
+            .db
+            .collection::<Document>(OUTBOX)
+            .find(filter, None)
+            .await?
+            .try_collect()
+            .await?;
+        for notification in &mut notifications {
+            let Ok(request) = notification.get_str("request") else {
+                continue;
+            };
+            let json = self.cipher.decrypt("request", request)?;
+            let request: NotificationRequest =
+                serde_json::from_str(&json).map_err(|_| CryptoError::Malformed)?;
+            notification.insert(
+                "request",
+                to_bson(&request).map_err(mongodb::error::Error::from)?,
+            );
+        }
+        Ok(notifications)
+    }
+
+    // Deletes what `find_personal_data` returns. The metadata goes last as it links the other
+    // documents to the email, an interrupted erasure can then be run again.
+    pub async fn erase_personal_data(&self, email: &str) -> mongodb::error::Result<ErasedData> {
+        let metadata = self.find_metadata_by_email(email).await?;
+        let in_tx_hashes = doc! { "tx_hash": { "$in": self.metadata_tx_hashes(&metadata).await? } };
+        let email_hash = doc! { "email_hash": self.cipher.blind_index(email) };
+        Ok(ErasedData {
+            email_groups: self
+                .email_groups()
+                .delete_many(in_tx_hashes.clone(), None)
+                .await?
+                .deleted_count,
+            notifications: self
+                .db
+                .collection::<Document>(OUTBOX)
+                .delete_many(in_tx_hashes, None)
+                .await?
+                .deleted_count,
+            newsletter: self
+                .newsletter()
+                .delete_many(email_hash.clone(), None)
+                .await?
+                .deleted_count,
+            metadata: self
+                .metadata()
+                .delete_many(email_hash, None)
+                .await?
+                .deleted_count,
+        })
+    }
+
+    pub async fn insert_data_request(
+        &self,
+        request: &DataRequestDoc,
+    ) -> mongodb::error::Result<()> {
+        self.db
+            .collection::<DataRequestDoc>(DATA_REQUESTS)
+            .insert_one(request, None)
+            .await?;
+        Ok(())
+    }
+
+    // Encrypts with the current key the personal data stored in plaintext or encrypted with an
+    // older key, returns how many documents were updated. Run after adding a key to rotate to.
+    pub async fn reencrypt(&self) -> Result<u64, RepositoryError> {
+        let mut updated = 0;
+        for (collection, fields) in ENCRYPTED_FIELDS {
+            updated += self.reencrypt_fields(collection, fields).await?;
+        }
+        Ok(updated)
+    }
+
+    // Migrations encrypt the fields they were written for, whatever ENCRYPTED_FIELDS became since
+    async fn reencrypt_fields(
+        &self,
+        collection: &str,
+        fields: &[&str],
+    ) -> Result<u64, RepositoryError> {
+        let mut updated = 0;
+        let collection = self.db.collection::<Document>(collection);
+        let mut documents = collection.find(None, None).await?;
+        while let Some(document) = documents.try_next().await? {
+            let mut update = Document::new();
+            for field in fields {
+                let Ok(value) = document.get_str(field) else {
+                    continue;
+                };
+                if !self.cipher.needs_rotation(value) {
+                    continue;
+                }
+                let plaintext = self.cipher.decrypt(field, value)?;
+                if *field == "email" {
+                    update.insert("email_hash", self.cipher.blind_index(&plaintext));
+                }
+                update.insert(*field, self.cipher.encrypt(field, &plaintext)?);
+            }
+            if update.is_empty() {
+                continue;
+            }
+            collection
+                .update_one(
+                    doc! { "_id": document.get("_id") },
+                    doc! { "$set": update },
+                    None,
+                )
+                .await?;
+            updated += 1;
+        }
+        Ok(updated)
+    }
+
+    // Whether the indexer saw a sale or an auto-renewal toggle with this meta_hash
+    pub async fn meta_hash_indexed(&self, meta_hash: &str) -> mongodb::error::Result<bool> {
+        let options = FindOneOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .build();
+        for collection in [SALES, AUTO_RENEW_UPDATES] {
+            let found = self
+                .db
+                .collection::<Document>(collection)
+                .find_one(doc! { "meta_hash": meta_hash }, options.clone())
+                .await?;
+            if found.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Verifies the unverified metadata still in its verification window whose sale or renewal was
+    // indexed since, returns how many were verified
+    pub async fn verify_pending_metadata(&self) -> mongodb::error::Result<u64> {
+        let pending: Vec<MetadataDoc> = self
+            .metadata()
+            .find(
+                doc! { "unverified": true, "verify_until": { "$gt": DateTime::now() } },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?;
+        let mut verified = 0;
+        for metadata in pending {
+            if !self.meta_hash_indexed(&metadata.meta_hash).await? {
+                continue;
+            }
+            self.metadata()
+                .update_one(
+                    doc! { "meta_hash": &metadata.meta_hash },
+                    doc! {
+                        "$set": { "unverified": false },
+                        "$unset": { "verify_until": "" },
+                    },
+                    None,
+                )
+                .await?;
+            verified += 1;
+        }
+        Ok(verified)
+    }
+
+    // Whether the indexer stored a sale or an auto-renewal toggle of this transaction
+    pub async fn transaction_exists(&self, tx_hash: &str) -> mongodb::error::Result<bool> {
+        let options = FindOneOptions::builder()
+            .projection(doc! { "_id": 1 })
+            .build();
+        for collection in [SALES, AUTO_RENEW_UPDATES] {
+            let found = self
+                .db
+                .collection::<Document>(collection)
+                .find_one(doc! { "tx_hash": tx_hash }, options.clone())
+                .await?;
+            if found.is_some() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Verifies the unverified email groups still in their verification window whose transaction
+    // was indexed since, returns how many were verified
+    pub async fn verify_pending_email_groups(&self) -> mongodb::error::Result<u64> {
+        let pending: Vec<EmailGroupDoc> = self
+            .email_groups()
+            .find(
+                doc! { "unverified": true, "verify_until": { "$gt": DateTime::now() } },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?;
+        let mut verified = 0;
+        for email_group in pending {
+            if !self.transaction_exists(&email_group.tx_hash).await? {
+                continue;
+            }
+            self.email_groups()
+                .update_one(
+                    doc! { "tx_hash": &email_group.tx_hash, "group": &email_group.group },
+                    doc! {
+                        "$set": { "unverified": false },
+                        "$unset": { "verify_until": "" },
+                    },
+                    None,
+                )
+                .await?;
+            verified += 1;
+        }
+        Ok(verified)
+    }
+
+    // One transaction can buy several domains, hence several sales
+    pub async fn find_sales(&self, tx_hash: &str) -> mongodb::error::Result<Vec<IndexedSaleDoc>> {
+        self.sales()
+            .find(doc! { "tx_hash": tx_hash }, None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    // Sales with metadata that were not processed yet, along with the groups chosen in their tx
+    pub async fn unprocessed_sales(&self) -> mongodb::error::Result<Cursor<SaleDoc>> {
+        Pipeline::on(SALES)
+            .filter(doc! { "meta_hash": { "$ne": "" } })
+            .join_where(
+                METADATA,
+                &["meta_hash"],
+                doc! { "unverified": { "$ne": true } },
+                &METADATA_FIELDS,
+                "metadata",
+            )
+            .require_joined("metadata")
+            .exclude_joined(Ledger::Purchases.collection_name(), &["tx_hash", "domain"])
+            .join_values_where(
+                EMAIL_GROUPS,
+                &["tx_hash"],
+                doc! { "unverified": { "$ne": true } },
+                "group",
+                "same_tx_groups",
+            )
+            .project(&SALE_FIELDS)
+            .run(&self.db)
+            .await
+    }
+
+    // Auto-renewal toggles with metadata that were not processed yet. Disabling keeps the
+    // meta_hash of the last enabling, so both directions join their metadata.
+    pub async fn unprocessed_renewals(&self) -> mongodb::error::Result<Cursor<ReenewalToggledDoc>> {
+        Pipeline::on(AUTO_RENEW_UPDATES)
+            .filter(doc! {
+                "meta_hash": { "$exists": true, "$ne": "" },
+                "tx_hash": { "$exists": true }
+            })
+            .join_where(
+                METADATA,
+                &["meta_hash"],
+                doc! { "unverified": { "$ne": true } },
+                &METADATA_FIELDS,
+                "metadata",
+            )
+            .require_joined("metadata")
+            .exclude_joined(Ledger::Renewals.collection_name(), &["tx_hash", "domain"])
+            .join_values_where(
+                EMAIL_GROUPS,
+                &["tx_hash"],
+                doc! { "unverified": { "$ne": true } },
+                "group",
+                "same_tx_groups",
+            )
+            .project(&RENEWAL_FIELDS)
+            .run(&self.db)
+            .await
+    }
+
+    // Upserts so a replayed key is a no-op
+    pub async fn mark_processed(
+        &self,
+        ledger: Ledger,
+        tx_hash: &str,
+        domain: &str,
+    ) -> mongodb::error::Result<()> {
+        self.processed(ledger)
+            .update_one(
+                processed_key(tx_hash, domain),
+                doc! { "$setOnInsert": { "processed_at": DateTime::now() } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Indexed sales of a domain and/or transaction, the latest first
+    pub async fn search_sales(
+        &self,
+        domain: Option<&str>,
+        tx_hash: Option<&str>,
+        offset: u64,
+        limit: i64,
+    ) -> mongodb::error::Result<Vec<IndexedSaleDoc>> {
+        let mut filter = doc! {};
+        if let Some(domain) = domain {
+            filter.insert("domain", domain);
+        }
+        if let Some(tx_hash) = tx_hash {
+            filter.insert("tx_hash", tx_hash);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! { "timestamp": -1, "_id": -1 })
+            .skip(offset)
+            .limit(limit)
+            .build();
+        self.sales()
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    pub async fn find_processed(
+        &self,
+        ledger: Ledger,
+        tx_hash: &str,
+        domain: &str,
+    ) -> mongodb::error::Result<Option<ProcessedDoc>> {
+        self.processed(ledger)
+            .find_one(processed_key(tx_hash, domain), None)
+            .await
+    }
+
+    // Records a notification as pending, does nothing if it was already recorded
+    pub async fn enqueue_notification(
+        &self,
+        kind: NotificationKind,
+        tx_hash: &str,
+        domain: &str,
+        request: &NotificationRequest,
+    ) -> Result<(), RepositoryError> {
+        let email_hash = request.email().map(|email| self.cipher.blind_index(email));
+        let request = self.encrypt_request(request)?;
+        let pending = to_bson(&NotificationState::Pending).map_err(mongodb::error::Error::from)?;
+        let now = DateTime::now();
+        self.outbox()
+            .update_one(
+                notification_key(kind, tx_hash, domain)?,
+                doc! {
+                    "$setOnInsert": {
+                        "request": request,
+                        "email_hash": email_hash,
+                        "state": pending,
+                        "attempts": 0,
+                        "next_attempt_at": now,
+                        "last_error": null,
+                        "created_at": now,
+                        "updated_at": now,
+                    }
+                },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Pending and failed notifications whose next attempt is due, the most overdue first
+    pub async fn due_notifications(
+        &self,
+        limit: i64,
+    ) -> mongodb::error::Result<Vec<NotificationDoc>> {
+        let filter = doc! {
+            "state": {
+                "$in": [
+                    to_bson(&NotificationState::Pending)?,
+                    to_bson(&NotificationState::Failed)?,
+                ]
+            },
+            "next_attempt_at": { "$lte": DateTime::now() },
+        };
+        let options = FindOptions::builder()
+            .sort(doc! { "next_attempt_at": 1 })
+            .limit(limit)
+            .build();
+        self.outbox()
+            .find(filter, options)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    // Whether a notification of the same email enqueued before `notification` is still to be
+    // sent. Dead notifications are not waited for.
+    pub async fn has_earlier_notification(
+        &self,
+        notification: &NotificationDoc,
+    ) -> mongodb::error::Result<bool> {
+        let Some(email_hash) = &notification.email_hash else {
+            return Ok(false);
+        };
+        let filter = doc! {
+            "email_hash": email_hash,
+            "state": {
+                "$in": [
+                    to_bson(&NotificationState::Pending)?,
+                    to_bson(&NotificationState::Failed)?,
+                ]
+            },
+            "$or": [
+                { "created_at": { "$lt": notification.created_at } },
+                { "created_at": notification.created_at, "_id": { "$lt": notification.id } },
+            ],
+        };
+        Ok(self.outbox().find_one(filter, None).await?.is_some())
+    }
+
+    pub async fn mark_notification_sent(
+        &self,
+        notification: &NotificationDoc,
+    ) -> mongodb::error::Result<()> {
+        self.outbox()
+            .update_one(
+                notification_key(
+                    notification.kind,
+                    &notification.tx_hash,
+                    &notification.domain,
+                )?,
+                doc! {
+                    "$set": {
+                        "state": to_bson(&NotificationState::Sent)?,
+                        "request": null,
+                        "last_error": null,
+                        "updated_at": DateTime::now(),
+                    },
+                    "$inc": { "attempts": 1 },
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Records a failed attempt, `state` tells whether the notification is retried. Dead
+    // notifications keep their request so they can be resent.
+    pub async fn mark_notification_failed(
+        &self,
+        notification: &NotificationDoc,
+        state: NotificationState,
+        error: &str,
+        next_attempt_at: DateTime,
+    ) -> mongodb::error::Result<()> {
+        let set = doc! {
+            "state": to_bson(&state)?,
+            "last_error": error,
+            "next_attempt_at": next_attempt_at,
+            "updated_at": DateTime::now(),
+        };
+        self.outbox()
+            .update_one(
+                notification_key(
+                    notification.kind,
+                    &notification.tx_hash,
+                    &notification.domain,
+                )?,
+                doc! { "$set": set, "$inc": { "attempts": 1 } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Outbox entries of a sale or renewal
+    pub async fn find_notifications(
+        &self,
+        tx_hash: &str,
+        domain: &str,
+    ) -> mongodb::error::Result<Vec<NotificationDoc>> {
+        self.outbox()
+            .find(processed_key(tx_hash, domain), None)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    // Makes a failed or dead notification due again with a fresh retry budget. Returns whether it
+    // was found with its request, which sent notifications no longer have.
+    pub async fn resend_notification(
+        &self,
+        kind: NotificationKind,
+        tx_hash: &str,
+        domain: &str,
+    ) -> mongodb::error::Result<bool> {
+        let mut filter = notification_key(kind, tx_hash, domain)?;
+        filter.insert("request", doc! { "$type": "string" });
+        let now = DateTime::now();
+        let result = self
+            .outbox()
+            .update_one(
+                filter,
+                doc! {
+                    "$set": {
+                        "state": to_bson(&NotificationState::Pending)?,
+                        "attempts": 0,
+                        "next_attempt_at": now,
+                        "last_error": null,
+                        "updated_at": now,
+                    }
+                },
+                None,
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    // Forgets that a sale or renewal was processed, along with its outbox entry, so sale_actions
+    // builds its notification again from the current metadata. Returns whether it was processed.
+    pub async fn purge_processed(
+        &self,
+        kind: NotificationKind,
+        tx_hash: &str,
+        domain: &str,
+    ) -> mongodb::error::Result<bool> {
+        self.outbox()
+            .delete_one(notification_key(kind, tx_hash, domain)?, None)
+            .await?;
+        let result = self
+            .processed(kind.ledger())
+            .delete_one(processed_key(tx_hash, domain), None)
+            .await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    // Newsletter subscriptions in the order they were stored, returned decrypted
+    pub async fn list_newsletters(
+        &self,
+        offset: u64,
+        limit: i64,
+    ) -> Result<Vec<NewsletterDoc>, RepositoryError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .skip(offset)
+            .limit(limit)
+            .build();
+        let newsletters: Vec<NewsletterDoc> = self
+            .newsletter()
+            .find(doc! {}, options)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(newsletters
+            .iter()
+            .map(|newsletter| self.decrypt_newsletter(newsletter))
+            .collect::<Result<_, _>>()?)
+    }
+
+    pub async fn count_newsletters(&self) -> mongodb::error::Result<u64> {
+        self.newsletter().count_documents(doc! {}, None).await
+    }
+}
+
+#[cfg(test)]
+mod repository_tests {
+    use super::{
+        migrations,
+        test_utils::{cipher, encryption, test_db},
+        EmailGroupDoc, EmailGroupsInsert, ErasedData, IndexedRenewalDoc, IndexedSaleDoc, Ledger,
+        MetadataDoc, MetadataUpsert, NewsletterDoc, NewsletterStatus, NotificationKind,
+        NotificationRequest, NotificationState, ReenewalToggledDoc, Repository, SaleDoc,
+        AUTO_RENEW_UPDATES, METADATA, METADATA_FIELDS, NEWSLETTER, RENEWAL_FIELDS, SALES,
+        SALE_FIELDS,
+    };
+    use crate::{crypto::Cipher, email::EmailRequest, metadata_hash::HashVersion};
+    use futures::stream::TryStreamExt;
+    use mongodb::bson::{doc, oid::ObjectId, to_document, DateTime, Document};
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    fn keys<T: Serialize>(value: &T) -> Vec<String> {
+        to_document(value).unwrap().keys().cloned().collect()
+    }
+
+    fn metadata() -> MetadataDoc {
+        MetadataDoc {
+            meta_hash: "abc".to_string(),
+            email: "john@example.com".to_string(),
+            tax_state: "FR".to_string(),
+            salt: "0x1".to_string(),
+            email_hash: String::new(),
+            hash_version: HashVersion::V1,
+            unverified: false,
+            verify_until: None,
+        }
+    }
+
+    #[test]
+    fn test_projections_match_documents() {
+        let sale = SaleDoc {
+            tx_hash: "0x1".to_string(),
+            domain: "john.stark".to_string(),
+            price: 10.0,
+            payer: "0x456".to_string(),
+            timestamp: 1700000000,
+            expiry: 1731536000,
+            metadata: vec![metadata()],
+            same_tx_groups: vec![],
+        };
+        let renewal = ReenewalToggledDoc {
+            tx_hash: "0x1".to_string(),
+            domain: "john.stark".to_string(),
+            renewer: "0x456".to_string(),
+            allowance: "1000".to_string(),
+            metadata: vec![metadata()],
+            same_tx_groups: vec![],
+        };
+        // the verification state is not needed to send emails
+        let mut metadata_keys = keys(&metadata());
+        metadata_keys.retain(|key| key != "hash_version" && key != "unverified");
+        assert_eq!(metadata_keys, METADATA_FIELDS);
+        assert_eq!(keys(&sale), SALE_FIELDS);
+        assert_eq!(keys(&renewal), RENEWAL_FIELDS);
+    }
+
+    #[test]
+    fn test_indexer_documents_deserialize() {
+        let sale: Document = doc! {
+            "_id": ObjectId::new(),
+            "tx_hash": "0x1",
+            "meta_hash": "abc",
+            "domain": "john.stark",
+            "token": "0x0",
+            "price": 10.0,
+            "payer": "0x456",
+            "timestamp": 1700000000_i64,
+            "expiry": 1731536000_i64,
+        };
+        let renewal: Document = doc! {
+            "_id": ObjectId::new(),
+            "tx_hash": "0x2",
+            "domain": "john.stark",
+            "renewer": "0x456",
+            "allowance": "1000",
+        };
+        let sale: IndexedSaleDoc = mongodb::bson::from_document(sale).unwrap();
+        let renewal: IndexedRenewalDoc = mongodb::bson::from_document(renewal).unwrap();
+        assert_eq!(sale.domain, "john.stark");
+        assert_eq!(sale.expiry, 1731536000);
+        assert_eq!(renewal.meta_hash, None);
+    }
+
+    #[tokio::test]
+    async fn test_upsert_metadata_detects_replays_and_conflicts() {
+        let Some(db) = test_db("upsert_metadata").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+
+        let conflicting = MetadataDoc {
+            email: "jane@example.com".to_string(),
+            ..metadata()
+        };
+        assert_eq!(
+            repo.upsert_metadata(&metadata()).await.unwrap(),
+            MetadataUpsert::Inserted
+        );
+        assert_eq!(
+            repo.upsert_metadata(&metadata()).await.unwrap(),
+            MetadataUpsert::Replayed
+        );
+        assert_eq!(
+            repo.upsert_metadata(&conflicting).await.unwrap(),
+            MetadataUpsert::Conflict
+        );
+        assert_eq!(
+            repo.metadata().count_documents(None, None).await.unwrap(),
+            1
+        );
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unverified_metadata_is_not_mailed_until_its_sale_is_indexed() {
+        let Some(db) = test_db("verify_metadata").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        let in_an_hour = DateTime::from_millis(DateTime::now().timestamp_millis() + 3_600_000);
+        let unverified = |meta_hash: &str, verify_until: DateTime| MetadataDoc {
+            meta_hash: meta_hash.to_string(),
+            unverified: true,
+            verify_until: Some(verify_until),
+            ..metadata()
+        };
+        repo.upsert_metadata(&unverified("abc", in_an_hour))
+            .await
+            .unwrap();
+        repo.upsert_metadata(&unverified("expired", DateTime::from_millis(0)))
+            .await
+            .unwrap();
+        let sale = |meta_hash: &str| {
+            doc! {
+                "tx_hash": format!("0x{}", meta_hash),
+                "meta_hash": meta_hash,
+                "domain": "john.stark",
+                "price": 10.0,
+                "payer": "0x456",
+                "timestamp": 1700000000_i64,
+                "expiry": 1731536000_i64,
+            }
+        };
+        db.collection::<Document>(SALES)
+            .insert_many([sale("abc"), sale("expired")], None)
+            .await
+            .unwrap();
+
+        let sales: Vec<SaleDoc> = repo
+            .unprocessed_sales()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(sales.is_empty());
+
+        assert_eq!(repo.verify_pending_metadata().await.unwrap(), 1);
+        let sales: Vec<SaleDoc> = repo
+            .unprocessed_sales()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(sales.len(), 1);
+        assert_eq!(sales[0].metadata[0].meta_hash, "abc");
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unverified_metadata_is_verified_by_a_renewal() {
+        let Some(db) = test_db("verify_renewal_metadata").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        let in_an_hour = DateTime::from_millis(DateTime::now().timestamp_millis() + 3_600_000);
+        repo.upsert_metadata(&MetadataDoc {
+            unverified: true,
+            verify_until: Some(in_an_hour),
+            ..metadata()
+        })
+        .await
+        .unwrap();
+        assert!(!repo.meta_hash_indexed("abc").await.unwrap());
+        db.collection::<Document>(AUTO_RENEW_UPDATES)
+            .insert_one(
+                doc! {
+                    "tx_hash": "0x1",
+                    "domain": "john.stark",
+                    "renewer": "0x456",
+                    "allowance": "1000",
+                    "meta_hash": "abc",
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert!(repo.meta_hash_indexed("abc").await.unwrap());
+        assert_eq!(repo.verify_pending_metadata().await.unwrap(), 1);
+        let renewals: Vec<ReenewalToggledDoc> = repo
+            .unprocessed_renewals()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(renewals.len(), 1);
+        assert_eq!(renewals[0].metadata[0].meta_hash, "abc");
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wallets_of_an_email_are_found() {
+        let Some(db) = test_db("email_wallets").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        repo.upsert_metadata(&metadata()).await.unwrap();
+        let sale = |tx_hash: &str, meta_hash: &str, payer: &str| {
+            doc! {
+                "tx_hash": tx_hash,
+                "domain": "john.stark",
+                "payer": payer,
+                "meta_hash": meta_hash,
+            }
+        };
+        db.collection::<Document>(SALES)
+            .insert_many(
+                [sale("0x1", "abc", "0x456"), sale("0x2", "def", "0x999")],
+                None,
+            )
+            .await
+            .unwrap();
+        db.collection::<Document>(AUTO_RENEW_UPDATES)
+            .insert_one(
+                doc! { "tx_hash": "0x3", "domain": "john.stark", "renewer": "0x789", "meta_hash": "abc" },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let email_hash = repo.cipher().blind_index("john@example.com");
+        let mut wallets = repo.find_email_wallets(&email_hash).await.unwrap();
+        wallets.sort();
+        assert_eq!(wallets, vec!["0x456", "0x789"]);
+        assert!(repo.find_email_wallets("unknown").await.unwrap().is_empty());
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_email_groups_already_stored_are_reported() {
+        let Some(db) = test_db("email_groups").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        let email_group = |group: &str| EmailGroupDoc {
+            tx_hash: "0x1".to_string(),
+            group: group.to_string(),
+            unverified: false,
+            verify_until: None,
+        };
+        assert_eq!(
+            repo.insert_email_groups(&[email_group("1")]).await.unwrap(),
+            EmailGroupsInsert {
+                added: vec!["1".to_string()],
+                existing: vec![],
+            }
+        );
+        assert_eq!(
+            repo.insert_email_groups(&[email_group("1"), email_group("2"), email_group("3")])
+                .await
+                .unwrap(),
+            EmailGroupsInsert {
+                added: vec!["2".to_string(), "3".to_string()],
+                existing: vec!["1".to_string()],
+            }
+        );
+        assert_eq!(
+            repo.email_groups()
+                .count_documents(None, None)
+                .await
+                .unwrap(),
+            3
+        );
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unverified_email_groups_are_not_joined_until_their_transaction_is_indexed() {
+        let Some(db) = test_db("verify_email_groups").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        repo.upsert_metadata(&metadata()).await.unwrap();
+        let in_an_hour = DateTime::from_millis(DateTime::now().timestamp_millis() + 3_600_000);
+        repo.insert_email_groups(&[EmailGroupDoc {
+            tx_hash: "0x1".to_string(),
+            group: "1".to_string(),
+            unverified: true,
+            verify_until: Some(in_an_hour),
+        }])
+        .await
+        .unwrap();
+        assert!(!repo.transaction_exists("0x1").await.unwrap());
+        assert_eq!(repo.verify_pending_email_groups().await.unwrap(), 0);
+
+        db.collection::<Document>(SALES)
+            .insert_one(
+                doc! {
+                    "tx_hash": "0x1",
+                    "meta_hash": "abc",
+                    "domain": "john.stark",
+                    "price": 10.0,
+                    "payer": "0x456",
+                    "timestamp": 1700000000_i64,
+                    "expiry": 1731536000_i64,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        let sales: Vec<SaleDoc> = repo
+            .unprocessed_sales()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(sales[0].same_tx_groups.is_empty());
+
+        assert!(repo.transaction_exists("0x1").await.unwrap());
+        assert_eq!(repo.verify_pending_email_groups().await.unwrap(), 1);
+        let sales: Vec<SaleDoc> = repo
+            .unprocessed_sales()
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(sales[0].same_tx_groups, vec!["1"]);
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_personal_data_is_encrypted_and_rotated() {
+        let Some(db) = test_db("encryption").await else {
+            return;
+        };
+        db.collection::<Document>(NEWSLETTER)
+            .insert_one(
+                doc! { "email": "jane@example.com", "address": null, "source": "legacy" },
+                None,
+            )
+            .await
+            .unwrap();
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        repo.upsert_metadata(&metadata()).await.unwrap();
+        let newsletter = NewsletterDoc {
+            email: "john@example.com".to_string(),
+            email_hash: String::new(),
+            address: Some("0x456".to_string()),
+            source: "newsletter_subscription".to_string(),
+            status: NewsletterStatus::Active,
+            confirm_until: None,
+        };
+        repo.insert_newsletter(&newsletter).await.unwrap();
+        assert!(repo.insert_newsletter(&newsletter).await.is_err());
+
+        let stored = db
+            .collection::<Document>(METADATA)
+            .find_one(None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.get_str("email").unwrap().starts_with("enc:k1:"));
+        assert!(stored.get_str("tax_state").unwrap().starts_with("enc:k1:"));
+        assert_eq!(
+            repo.find_newsletter("jane@example.com")
+                .await
+                .unwrap()
+                .unwrap()
+                .source,
+            "legacy"
+        );
+
+        let rotated = Repository::new(db.clone(), Cipher::new(&encryption("k2")).unwrap());
+        assert_eq!(rotated.reencrypt().await.unwrap(), 3);
+        assert_eq!(rotated.reencrypt().await.unwrap(), 0);
+        let found = rotated.find_newsletter("john@example.com").await.unwrap();
+        assert_eq!(found.unwrap().address.as_deref(), Some("0x456"));
+        assert_eq!(
+            rotated.upsert_metadata(&metadata()).await.unwrap(),
+            MetadataUpsert::Replayed
+        );
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_personal_data_is_exported_and_erased() {
+        let Some(db) = test_db("personal_data").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        repo.upsert_metadata(&metadata()).await.unwrap();
+        db.collection::<Document>(SALES)
+            .insert_one(doc! { "tx_hash": "0x1", "meta_hash": "abc" }, None)
+            .await
+            .unwrap();
+        let email_group = |tx_hash: &str, group: &str| EmailGroupDoc {
+            tx_hash: tx_hash.to_string(),
+            group: group.to_string(),
+            unverified: false,
+            verify_until: None,
+        };
+        repo.insert_email_groups(&[
+            email_group("0x1", "1"),
+            email_group("0x1", "2"),
+            email_group("0x2", "1"),
+        ])
+        .await
+        .unwrap();
+
+        let data = repo.find_personal_data("john@example.com").await.unwrap();
+        assert_eq!(data.metadata[0].email, "john@example.com");
+        assert_eq!(data.newsletter, None);
+        assert_eq!(data.email_groups.len(), 2);
+
+        let erased = repo.erase_personal_data("john@example.com").await.unwrap();
+        assert_eq!(
+            erased,
+            ErasedData {
+                metadata: 1,
+                newsletter: 0,
+                email_groups: 2,
+                notifications: 0,
+            }
+        );
+        let data = repo.find_personal_data("john@example.com").await.unwrap();
+        assert!(data.metadata.is_empty() && data.email_groups.is_empty());
+        assert_eq!(
+            repo.email_groups()
+                .count_documents(None, None)
+                .await
+                .unwrap(),
+            1
+        );
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_email_groups_are_found_and_removed_by_email_hash() {
+        let Some(db) = test_db("preferences").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        let email_hash = repo.cipher().blind_index("john@example.com");
+        assert_eq!(repo.find_email(&email_hash).await.unwrap(), None);
+
+        repo.upsert_metadata(&metadata()).await.unwrap();
+        db.collection::<Document>(SALES)
+            .insert_one(doc! { "tx_hash": "0x1", "meta_hash": "abc" }, None)
+            .await
+            .unwrap();
+        let email_group = |tx_hash: &str, group: &str| EmailGroupDoc {
+            tx_hash: tx_hash.to_string(),
+            group: group.to_string(),
+            unverified: false,
+            verify_until: None,
+        };
+        repo.insert_email_groups(&[
+            email_group("0x1", "1"),
+            email_group("0x1", "2"),
+            email_group("0x2", "1"),
+        ])
+        .await
+        .unwrap();
+
+        assert_eq!(
+            repo.find_email(&email_hash).await.unwrap().as_deref(),
+            Some("john@example.com")
+        );
+        assert_eq!(repo.find_email_groups(&email_hash).await.unwrap().len(), 2);
+        assert_eq!(
+            repo.remove_email_groups(&email_hash, &["1".to_string()])
+                .await
+                .unwrap(),
+            1
+        );
+        let groups = repo.find_email_groups(&email_hash).await.unwrap();
+        assert_eq!(groups, vec![email_group("0x1", "2")]);
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sales_are_searched_by_domain_and_transaction() {
+        let Some(db) = test_db("search_sales").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        let sale = |tx_hash: &str, domain: &str, timestamp: i64| IndexedSaleDoc {
+            tx_hash: tx_hash.to_string(),
+            meta_hash: "abc".to_string(),
+            domain: domain.to_string(),
+            price: 10.0,
+            payer: "0x456".to_string(),
+            timestamp,
+            expiry: 1731536000,
+        };
+        let (first, second, third) = (
+            sale("0x1", "john.stark", 1),
+            sale("0x1", "jane.stark", 1),
+            sale("0x2", "john.stark", 2),
+        );
+        repo.sales()
+            .insert_many([&first, &second, &third], None)
+            .await
+            .unwrap();
+
+        let found = repo
+            .search_sales(Some("john.stark"), None, 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(found, vec![third.clone(), first.clone()]);
+        let found = repo.search_sales(None, Some("0x1"), 0, 10).await.unwrap();
+        assert_eq!(found, vec![second.clone(), first.clone()]);
+        let found = repo
+            .search_sales(Some("john.stark"), Some("0x1"), 0, 10)
+            .await
+            .unwrap();
+        assert_eq!(found, vec![first]);
+        let found = repo.search_sales(None, None, 1, 1).await.unwrap();
+        assert_eq!(found, vec![second]);
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_notifications_are_resent_and_purged() {
+        let Some(db) = test_db("admin_notifications").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        let kind = NotificationKind::Purchase;
+        let request = NotificationRequest::Email(EmailRequest::UpsertSubscriber {
+            email: "john@example.com".to_string(),
+            fields: BTreeMap::new(),
+            groups: vec![],
+        });
+        repo.enqueue_notification(kind, "0x1", "john.stark", &request)
+            .await
+            .unwrap();
+        repo.enqueue_notification(kind, "0x1", "john.stark", &request)
+            .await
+            .unwrap();
+        repo.mark_processed(Ledger::Purchases, "0x1", "john.stark")
+            .await
+            .unwrap();
+
+        let due = repo.due_notifications(10).await.unwrap();
+        assert_eq!(due.len(), 1);
+        assert!(due[0].request.as_deref().unwrap().starts_with("enc:"));
+        assert_eq!(
+            due[0].email_hash,
+            Some(repo.cipher().blind_index("john@example.com"))
+        );
+        assert_eq!(repo.decrypt_request(&due[0]), Ok(request.clone()));
+        let in_an_hour = DateTime::from_millis(DateTime::now().timestamp_millis() + 3_600_000);
+        repo.mark_notification_failed(&due[0], NotificationState::Dead, "rate limited", in_an_hour)
+            .await
+            .unwrap();
+        assert!(repo.due_notifications(10).await.unwrap().is_empty());
+        // dead notifications keep their request
+        let found = repo.find_notifications("0x1", "john.stark").await.unwrap();
+        assert_eq!(repo.decrypt_request(&found[0]), Ok(request));
+
+        assert!(repo
+            .resend_notification(kind, "0x1", "john.stark")
+            .await
+            .unwrap());
+        assert!(!repo
+            .resend_notification(NotificationKind::Renewal, "0x1", "john.stark")
+            .await
+            .unwrap());
+        let due = repo.due_notifications(10).await.unwrap();
+        assert_eq!(
+            (due[0].state, due[0].attempts, due[0].last_error.clone()),
+            (NotificationState::Pending, 0, None)
+        );
+
+        // the request is not kept once sent, the notification can't be resent anymore
+        repo.mark_notification_sent(&due[0]).await.unwrap();
+        let found = repo.find_notifications("0x1", "john.stark").await.unwrap();
+        assert_eq!(
+            (found[0].state, found[0].request.clone()),
+            (NotificationState::Sent, None)
+        );
+        assert!(!repo
+            .resend_notification(kind, "0x1", "john.stark")
+            .await
+            .unwrap());
+
+        assert!(repo
+            .find_processed(Ledger::Purchases, "0x1", "john.stark")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(repo
+            .purge_processed(kind, "0x1", "john.stark")
+            .await
+            .unwrap());
+        assert!(!repo
+            .purge_processed(kind, "0x1", "john.stark")
+            .await
+            .unwrap());
+        assert!(repo
+            .find_notifications("0x1", "john.stark")
+            .await
+            .unwrap()
+            .is_empty());
+        db.drop(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_newsletters_are_listed_by_page() {
+        let Some(db) = test_db("list_newsletters").await else {
+            return;
+        };
+        let repo = Repository::new(db.clone(), cipher());
+        migrations::run(&repo).await.unwrap();
+        for email in ["a@example.com", "b@example.com", "c@example.com"] {
+            let newsletter = NewsletterDoc {
+                email: email.to_string(),
+                email_hash: String::new(),
+                address: None,
+                source: "newsletter_subscription".to_string(),
+                status: NewsletterStatus::Active,
+                confirm_until: None,
+            };
+            repo.insert_newsletter(&newsletter).await.unwrap();
+        }
+
+        assert_eq!(repo.count_newsletters().await.unwrap(), 3);
+        let page = repo.list_newsletters(1, 5).await.unwrap();
+        let emails: Vec<&str> = page.iter().map(|n| n.email.as_str()).collect();
+        assert_eq!(emails, vec!["b@example.com", "c@example.com"]);
+        db.drop(None).await.unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use super::Ledger;
+use crate::{email::EmailRequest, metadata_hash::HashVersion};
+use mongodb::bson::{oid::ObjectId, DateTime, Document};
+use serde::{Deserialize, Serialize};
+
+// Documents written by the indexer, only the fields we read are listed
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexedSaleDoc {
+    pub tx_hash: String,
+    pub meta_hash: String,
+    pub domain: String,
+    pub price: f64,
+    pub payer: String,
+    pub timestamp: i64,
+    pub expiry: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexedRenewalDoc {
+    pub tx_hash: String,
+    pub domain: String,
+    pub renewer: String,
+    pub allowance: String,
+    pub meta_hash: Option<String>,
+}
+
+// Documents written by the API
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetadataDoc {
+    pub meta_hash: String,
+    pub email: String,
+    pub tax_state: String,
+    pub salt: String,
+    // Keyed hash of the email to look it up, set by the repository which encrypts the email
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub email_hash: String,
+    // Scheme used to compute the meta_hash, metadata stored before it was versioned used v1
+    #[serde(default)]
+    pub hash_version: HashVersion,
+    // Set when no sale or renewal with this meta_hash was indexed yet, such metadata is not mailed
+    #[serde(default)]
+    pub unverified: bool,
+    // Unverified metadata is matched against new sales until then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_until: Option<DateTime>,
+}
+
+impl MetadataDoc {
+    pub fn same_content(&self, other: &MetadataDoc) -> bool {
+        self.meta_hash == other.meta_hash
+            && self.email == other.email
+            && self.tax_state == other.tax_state
+            && self.salt == other.salt
+            && self.hash_version == other.hash_version
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmailGroupDoc {
+    pub tx_hash: String,
+    pub group: String,
+    // Set when no sale or renewal with this tx_hash was indexed yet, such groups are not joined
+    #[serde(default)]
+    pub unverified: bool,
+    // Unverified groups are matched against new transactions until then
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verify_until: Option<DateTime>,
+}
+
+// Subscriptions are pending until the email is confirmed, the ones stored before double opt-in
+// are active
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NewsletterStatus {
+    #[default]
+    Active,
+    Pending,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NewsletterDoc {
+    pub email: String,
+    // Keyed hash of the email to look it up, set by the repository which encrypts the email
+    #[serde(default)]
+    pub email_hash: String,
+    pub address: Option<String>,
+    pub source: String,
+    #[serde(default)]
+    pub status: NewsletterStatus,
+    // Pending subscriptions are purged once this passes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confirm_until: Option<DateTime>,
+}
+
+// Data subject requests, identified by the keyed hash of the email
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataRequestAction {
+    Export,
+    Erase,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ErasedData {
+    pub metadata: u64,
+    pub newsletter: u64,
+    pub email_groups: u64,
+    pub notifications: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DataRequestDoc {
+    pub action: DataRequestAction,
+    pub email_hash: String,
+    pub requested_at: DateTime,
+    pub erased: Option<ErasedData>,
+    pub error: Option<String>,
+}
+
+// Documents written by sale_actions
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProcessedDoc {
+    pub tx_hash: String,
+    pub domain: String,
+    pub processed_at: DateTime,
+}
+
+// Every notification goes through the outbox: it is first recorded as pending, then delivered
+// with retries until it is sent or considered dead.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationKind {
+    Purchase,
+    Renewal,
+}
+
+impl NotificationKind {
+    // Ledger recording that the notification was added to the outbox
+    pub fn ledger(&self) -> Ledger {
+        match self {
+            NotificationKind::Purchase => Ledger::Purchases,
+            NotificationKind::Renewal => Ledger::Renewals,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationState {
+    Pending,
+    Sent,
+    Failed,
+    Dead,
+}
+
+// What a notification asks the email provider
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum NotificationRequest {
+    Email(EmailRequest),
+    // The subscriber of `email` is looked up when the notification is delivered, after the
+    // notifications enqueued before it for the same email
+    RemoveEmailFromGroup { email: String, group: String },
+}
+
+impl NotificationRequest {
+    // Email the request is about, when it is known without asking the provider
+    pub fn email(&self) -> Option<&str> {
+        match self {
+            NotificationRequest::Email(EmailRequest::UpsertSubscriber { email, .. })
+            | NotificationRequest::RemoveEmailFromGroup { email, .. } => Some(email),
+            NotificationRequest::Email(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotificationDoc {
+    // Orders the notifications enqueued in the same millisecond
+    #[serde(rename = "_id", skip_serializing)]
+    pub id: ObjectId,
+    pub kind: NotificationKind,
+    pub tx_hash: String,
+    pub domain: String,
+    // Encrypted with `Repository::encrypt_request`, cleared once sent
+    pub request: Option<String>,
+    // Keyed hash of the email of the request, orders the notifications of an email
+    #[serde(default)]
+    pub email_hash: Option<String>,
+    pub state: NotificationState,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime,
+    pub last_error: Option<String>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+// Everything stored about an email, decrypted. Notifications are the outbox entries of its
+// transactions, which hold the requests sent to the email provider.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PersonalData {
+    pub metadata: Vec<MetadataDoc>,
+    pub newsletter: Option<NewsletterDoc>,
+    pub email_groups: Vec<EmailGroupDoc>,
+    pub notifications: Vec<Document>,
+}
+
+// Outputs of the aggregations listing what is left to notify
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SaleDoc {
+    pub tx_hash: String,
+    pub domain: String,
+    pub price: f64,
+    pub payer: String,
+    pub timestamp: i64,
+    pub expiry: i64,
+    pub metadata: Vec<MetadataDoc>,
+    pub same_tx_groups: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReenewalToggledDoc {
+    pub tx_hash: String,
+    pub domain: String,
+    pub renewer: String,
+    pub allowance: String,
+    pub metadata: Vec<MetadataDoc>,
+    pub same_tx_groups: Vec<String>,
+}
@@ -0,0 +1,178 @@
+use mongodb::{
+    bson::{doc, Bson, Document},
+    Collection, Cursor, Database,
+};
+use serde::de::DeserializeOwned;
+use std::marker::PhantomData;
+
+// Aggregation pipeline over `collection` whose output documents deserialize to `T`. Lookups
+// only keep the fields they are asked for, the final projection lists the fields of `T`.
+pub struct Pipeline<T> {
+    collection: &'static str,
+    stages: Vec<Document>,
+    output: PhantomData<T>,
+}
+
+// $expr matching documents whose `on` fields equal the ones of the joining document
+fn join_condition(on: &[&str]) -> Document {
+    let conditions: Vec<Bson> = on
+        .iter()
+        .map(|field| {
+            Bson::Document(doc! { "$eq": [format!("${}", field), format!("$${}", field)] })
+        })
+        .collect();
+    doc! { "$expr": { "$and": conditions } }
+}
+
+fn join_variables(on: &[&str]) -> Document {
+    on.iter()
+        .map(|field| (field.to_string(), Bson::String(format!("${}", field))))
+        .collect()
+}
+
+impl<T: DeserializeOwned + Unpin + Send + Sync> Pipeline<T> {
+    pub fn on(collection: &'static str) -> Self {
+        Pipeline {
+            collection,
+            stages: Vec::new(),
+            output: PhantomData,
+        }
+    }
+
+    pub fn filter(mut self, filter: Document) -> Self {
+        self.stages.push(doc! { "$match": filter });
+        self
+    }
+
+    // Adds the `fields` of the documents of `from` sharing the `on` fields as an array `as_field`
+    pub fn join(self, from: &str, on: &[&str], fields: &[&str], as_field: &str) -> Self {
+        self.join_where(from, on, Document::new(), fields, as_field)
+    }
+
+    // Like `join` but only with the documents of `from` also matching `filter`
+    pub fn join_where(
+        mut self,
+        from: &str,
+        on: &[&str],
+        filter: Document,
+        fields: &[&str],
+        as_field: &str,
+    ) -> Self {
+        let mut condition = join_condition(on);
+        condition.extend(filter);
+        let mut projection = doc! { "_id": 0 };
+        for field in fields {
+            projection.insert(*field, 1);
+        }
+        self.stages.push(doc! {
+            "$lookup": {
+                "from": from,
+                "let": join_variables(on),
+                "pipeline": [
+                    { "$match": condition },
+                    { "$project": projection },
+                ],
+                "as": as_field,
+            }
+        });
+        self
+    }
+
+    // Like `join` but flattens the joined documents to the values of their `field`
+    pub fn join_values(self, from: &str, on: &[&str], field: &str, as_field: &str) -> Self {
+        self.join_values_where(from, on, Document::new(), field, as_field)
+    }
+
+    // Like `join_values` but only with the documents of `from` also matching `filter`
+    pub fn join_values_where(
+        self,
+        from: &str,
+        on: &[&str],
+        filter: Document,
+        field: &str,
+        as_field: &str,
+    ) -> Self {
+        let mut pipeline = self.join_where(from, on, filter, &[field], as_field);
+        pipeline.stages.push(doc! {
+            "$addFields": {
+                as_field: {
+                    "$map": {
+                        "input": format!("${}", as_field),
+                        "as": "item",
+                        "in": format!("$$item.{}", field),
+                    }
+                }
+            }
+        });
+        pipeline
+    }
+
+    // Drops the documents for which the join `as_field` found nothing
+    pub fn require_joined(self, as_field: &str) -> Self {
+        self.filter(doc! { as_field: { "$ne": [] } })
+    }
+
+    // Drops the documents having a match in `from` on the `on` fields
+    pub fn exclude_joined(self, from: &str, on: &[&str]) -> Self {
+        let as_field = format!("{}_doc", from);
+        self.join(from, on, &[], &as_field)
+            .filter(doc! { as_field: { "$eq": [] } })
+    }
+
+    pub fn project(mut self, fields: &[&str]) -> Self {
+        let mut projection = doc! { "_id": 0 };
+        for field in fields {
+            projection.insert(*field, 1);
+        }
+        self.stages.push(doc! { "$project": projection });
+        self
+    }
+
+    pub fn stages(&self) -> &[Document] {
+        &self.stages
+    }
+
+    pub async fn run(self, db: &Database) -> mongodb::error::Result<Cursor<T>> {
+        let collection: Collection<Document> = db.collection(self.collection);
+        let cursor = collection.aggregate(self.stages, None).await?;
+        Ok(cursor.with_type())
+    }
+}
+
+#[cfg(test)]
+mod pipeline_tests {
+    use super::Pipeline;
+    use mongodb::bson::{doc, Document};
+
+    #[test]
+    fn test_exclude_joined_matches_on_every_field() {
+        let pipeline =
+            Pipeline::<Document>::on("sales").exclude_joined("processed", &["tx_hash", "domain"]);
+        assert_eq!(
+            pipeline.stages(),
+            &[
+                doc! {
+                    "$lookup": {
+                        "from": "processed",
+                        "let": { "tx_hash": "$tx_hash", "domain": "$domain" },
+                        "pipeline": [
+                            {
+                                "$match": {
+                                    "$expr": {
+                                        "$and": [
+                                            { "$eq": ["$tx_hash", "$$tx_hash"] },
+                                            { "$eq": ["$domain", "$$domain"] },
+                                        ]
+                                    }
+                                }
+                            },
+                            { "$project": { "_id": 0 } },
+                        ],
+                        "as": "processed_doc",
+                    }
+                },
+                doc! { "$match": { "processed_doc": { "$eq": [] } } },
+            ]
+        );
+    }
+}
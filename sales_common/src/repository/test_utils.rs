@@ -0,0 +1,43 @@
+use crate::{config::Encryption, crypto::Cipher};
+use mongodb::{options::ClientOptions, Client, Database};
+use std::collections::HashMap;
+use std::env;
+
+// Test keys "k1" and "k2", `current_key` being the one encrypting
+pub fn encryption(current_key: &str) -> Encryption {
+    Encryption {
+        current_key: current_key.to_string(),
+        keys: HashMap::from([
+            (
+                "k1".to_string(),
+                "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=".to_string(),
+            ),
+            (
+                "k2".to_string(),
+                "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA=".to_string(),
+            ),
+        ]),
+        index_key: "aW5kZXhpbmRleGluZGV4aW5kZXhpbmRleGluZGV4MTI=".to_string(),
+    }
+}
+
+pub fn cipher() -> Cipher {
+    Cipher::new(&encryption("k1")).unwrap()
+}
+
+// Fresh database on the MongoDB instance of MONGODB_TEST_URI. Without it, the tests using a
+// database are skipped: they return early and pass.
+pub async fn test_db(name: &str) -> Option<Database> {
+    let Ok(uri) = env::var("MONGODB_TEST_URI") else {
+        eprintln!("skipping {}, MONGODB_TEST_URI is not set", name);
+        return None;
+    };
+    let client = Client::with_options(ClientOptions::parse(&uri).await.unwrap()).unwrap();
+    let db = client.database(&format!(
+        "sales_test_{}_{}",
+        name,
+        chrono::Utc::now().timestamp_millis()
+    ));
+    db.drop(None).await.unwrap();
+    Some(db)
+}
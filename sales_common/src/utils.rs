@@ -29,6 +29,7 @@ pub fn to_hex(felt: FieldElement) -> String {
     result
 }
 
+#[cfg(test)]
 mod utils_tests {
     use super::to_hex;
     use starknet::core::types::FieldElement;